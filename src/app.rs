@@ -1,6 +1,11 @@
-use clap::{command, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 use crate::bitmask_mode::CollapseMode;
+use crate::dzi_archive::ArchiveFormat;
+use crate::dzi_layout::DZILayout;
+use crate::dzi_pyramid::ReductionFilter;
+use crate::dzi_sparse::DedupMode;
+use crate::tile_format::TileFormat;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -13,15 +18,118 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     BitmaskMode(BitmaskModeArgs),
+    BitmaskDZIMode(BitmaskDZIModeArgs),
     DZISplitMode(DZISplitModeArgs),
+    DZIVerify(DZIVerifyArgs),
+    DZIReassemble(DZIReassembleArgs),
+    Serve(ServeArgs),
 }
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct BitmaskModeArgs {
+    /// TOML or JSON job file to take options from. Its keys are the long option names, and options given on the
+    /// command line win.
+    #[arg(long = "config")]
+    pub config: Option<String>,
+    /// Profile in the job file's `profiles` table whose options replace the ones at the top of the file
+    #[arg(long = "profile", requires = "config")]
+    pub profile: Option<String>,
     #[arg(short, long = "dry-run", value_parser, default_value = "false")]
     pub dry_run: bool,
+    #[command(flatten)]
+    pub channels: ChannelArgs,
+    /// The output file name
+    #[arg(short, long = "out", default_value = "./output.png", required = true)]
+    pub output_file: String,
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(propagate_version = true)]
+pub struct BitmaskDZIModeArgs {
+    #[arg(short, long = "dry-run", value_parser, default_value = "false")]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub channels: ChannelArgs,
+
+    /// The output file name stem
+    #[arg(short = 's', long = "output-file-stem", default_value = "dzi")]
+    pub output_file_stem: String,
+
+    /// The output folder
+    #[arg(short, long = "output-folder", default_value = "output")]
+    pub output_folder: String,
+
+    /// DZI tile size
+    #[arg(short, long = "tile-size", default_value = "256")]
+    pub tile_size: u32,
+
+    /// Pixels each tile shares with its neighbours
+    #[arg(long = "overlap", default_value = "0")]
+    pub overlap: u32,
+
+    /// Tile rows each level buffers before scaling them down
+    #[arg(long = "buffer-rows", default_value = "1")]
+    pub buffer_rows: u32,
+
+    /// Threads used to encode and save the tiles of each level
+    #[arg(short, long = "jobs", default_value = "1")]
+    pub jobs: u32,
+
+    /// How the heatmap and pass-through channels are scaled down
+    #[arg(long = "filter", value_enum, default_value = "bilinear")]
+    pub filter: ReductionFilter,
+
+    /// How the bitmask channels are scaled down. Or keeps every class in a block, mode keeps the most common.
+    #[arg(long = "bitmask-filter", value_enum, default_value = "or")]
+    pub bitmask_filter: ReductionFilter,
+
+    /// Tile image format
+    #[arg(long = "format", value_enum, default_value = "png")]
+    pub format: TileFormat,
+
+    /// JPEG and WebP quality, from 0 to 100
+    #[arg(long = "quality", default_value = "90")]
+    pub quality: u8,
+
+    /// Output layout
+    #[arg(long = "layout", value_enum, default_value = "dzi")]
+    pub layout: DZILayout,
+
+    /// URL the output folder will be served from, used as the base of the IIIF image id
+    #[arg(long = "base-url", default_value = "")]
+    pub base_url: String,
+
+    /// Write the whole pyramid into one archive file
+    #[arg(long = "archive", value_enum)]
+    pub archive: Option<ArchiveFormat>,
+
+    /// Leave out tiles that are fully transparent or all opaque black, and list them in `{stem}_index.json`
+    #[arg(long = "skip-empty")]
+    pub skip_empty: bool,
+
+    /// Write tiles with the same content only once, and hard link or index the rest
+    #[arg(long = "dedup", value_enum)]
+    pub dedup: Option<DedupMode>,
+
+    /// Only write tiles that intersect this rectangle, given as x y width height in merged image pixels
+    #[arg(value_parser, num_args = 4, long = "roi")]
+    pub roi: Option<Vec<u32>>,
+
+    /// Only write tiles that intersect pixels of this image that aren't black or transparent
+    #[arg(long = "mask")]
+    pub mask: Option<String>,
+
+    /// Write an `index.html` next to the tiles that shows them offline, with a toggle for each bitmask class
+    #[arg(long = "emit-viewer", conflicts_with = "archive")]
+    pub emit_viewer: bool,
+}
+
+// The source images bitmask-mode merges, and how each becomes its channel
+#[derive(Args)]
+pub struct ChannelArgs {
     /// Paths to source images
     #[arg(short, long = "red-path")]
     pub red_channel_file_path: String,
@@ -54,12 +162,8 @@ pub struct BitmaskModeArgs {
         long = "source-dimensions"
     )]
     pub source_dim: Vec<u32>,
-    /// The output file name
-    #[arg(short, long = "out", default_value = "./output.png", required = true)]
-    pub output_file: String,
 }
 
-
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -81,8 +185,223 @@ pub struct DZISplitModeArgs {
     #[arg(short, long = "tile-size", default_value = "256")]
     pub tile_size: u32,
 
-    /// Layer to prepare
-    #[arg(short, long = "layer-to-prepare", default_value = "0")]
+    /// Pixels each tile shares with its neighbours
+    #[arg(long = "overlap", default_value = "0")]
+    pub overlap: u32,
+
+    /// Layer to prepare on its own. 0 prepares every layer.
+    #[arg(short, long = "layer-to-prepare", default_value = "0", conflicts_with_all = ["min_level", "max_level"])]
     pub layer_to_prepare: u32,
-    
-}
\ No newline at end of file
+
+    /// Coarsest layer to prepare, numbered as in the output layout
+    #[arg(long = "min-level")]
+    pub min_level: Option<u32>,
+
+    /// Finest layer to prepare. Layers above it are read back from disk if complete, or rebuilt from the input image.
+    #[arg(long = "max-level")]
+    pub max_level: Option<u32>,
+
+    /// Tile rows each level buffers before scaling them down. Peak memory grows with image width times this.
+    #[arg(long = "buffer-rows", default_value = "1")]
+    pub buffer_rows: u32,
+
+    /// Threads used to encode and save the tiles of each level. The output is the same for any number.
+    #[arg(short, long = "jobs", default_value = "1")]
+    pub jobs: u32,
+
+    /// How each level is scaled down from the one above. Use or/mode/nearest for bitmask and label images.
+    #[arg(long = "filter", value_enum, default_value = "bilinear")]
+    pub filter: ReductionFilter,
+
+    /// Tile image format. Auto writes PNG if the image has any transparency, and JPEG otherwise.
+    #[arg(long = "format", value_enum, default_value = "png")]
+    pub format: TileFormat,
+
+    /// JPEG and WebP quality, from 0 to 100
+    #[arg(long = "quality", default_value = "90")]
+    pub quality: u8,
+
+    /// Output layout: a DZI descriptor and `_files` tree, the legacy flat file names, XYZ or TMS map tiles, IIIF, Zoomify
+    /// or an OME-NGFF Zarr store
+    #[arg(long = "layout", value_enum, default_value = "dzi")]
+    pub layout: DZILayout,
+
+    /// Names of the channels a Zarr store keeps, in RGBA order. Channels past the last name are left out.
+    #[arg(long = "channel-labels", value_delimiter = ',', default_value = "red,green,blue,alpha")]
+    pub channel_labels: Vec<String>,
+
+    /// URL the output folder will be served from, used as the base of the IIIF image id
+    #[arg(long = "base-url", default_value = "")]
+    pub base_url: String,
+
+    /// Keep a progress manifest in the output folder, and skip the tiles it shows are already done
+    #[arg(long = "resume", conflicts_with_all = ["archive", "skip_empty", "dedup", "roi", "mask"])]
+    pub resume: bool,
+
+    /// Write the whole pyramid into one `{stem}.szi` zip, `{stem}.tar`, `{stem}.sqlite` database, `{stem}.mbtiles` for
+    /// the xyz and tms layouts, or pyramidal `{stem}.tif`
+    #[arg(long = "archive", value_enum)]
+    pub archive: Option<ArchiveFormat>,
+
+    /// Leave out tiles that are fully transparent or all opaque black, and list them in `{stem}_index.json`
+    #[arg(long = "skip-empty")]
+    pub skip_empty: bool,
+
+    /// Write tiles with the same content only once, and hard link or index the rest
+    #[arg(long = "dedup", value_enum)]
+    pub dedup: Option<DedupMode>,
+
+    /// Only write tiles that intersect this rectangle, given as x y width height in full resolution pixels
+    #[arg(value_parser, num_args = 4, long = "roi")]
+    pub roi: Option<Vec<u32>>,
+
+    /// Only write tiles that intersect pixels of this image that aren't black or transparent. It is stretched over
+    /// the whole input, so it can be a thumbnail.
+    #[arg(long = "mask")]
+    pub mask: Option<String>,
+
+    /// Write an `index.html` next to the tiles that shows them offline, from file:// with no network
+    #[arg(long = "emit-viewer", conflicts_with = "archive")]
+    pub emit_viewer: bool,
+
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(propagate_version = true)]
+pub struct DZIVerifyArgs {
+
+    /// The folder dzi-split-mode wrote to
+    #[arg(short, long = "output-folder", default_value = "output")]
+    pub output_folder: String,
+
+    /// The output file name stem it was given
+    #[arg(short = 's', long = "output-file-stem", default_value = "dzi")]
+    pub output_file_stem: String,
+
+    /// The layout it wrote
+    #[arg(long = "layout", value_enum, default_value = "dzi")]
+    pub layout: DZILayout,
+
+    /// Image width, for pyramids without a descriptor
+    #[arg(long = "width")]
+    pub width: Option<u32>,
+
+    /// Image height, for pyramids without a descriptor
+    #[arg(long = "height")]
+    pub height: Option<u32>,
+
+    /// Tile size, for pyramids without a descriptor. Defaults to 256.
+    #[arg(short, long = "tile-size")]
+    pub tile_size: Option<u32>,
+
+    /// Overlap, for pyramids without a descriptor. Defaults to 0.
+    #[arg(long = "overlap")]
+    pub overlap: Option<u32>,
+
+    /// Number of threads decoding tiles
+    #[arg(short, long = "jobs", default_value = "1")]
+    pub jobs: u32,
+
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(propagate_version = true)]
+pub struct DZIReassembleArgs {
+
+    /// The folder dzi-split-mode wrote to
+    #[arg(short, long = "output-folder", default_value = "output")]
+    pub output_folder: String,
+
+    /// The output file name stem it was given
+    #[arg(short = 's', long = "output-file-stem", default_value = "dzi")]
+    pub output_file_stem: String,
+
+    /// The layout it wrote
+    #[arg(long = "layout", value_enum, default_value = "dzi")]
+    pub layout: DZILayout,
+
+    /// Level to stitch together, numbered as in the output layout. Defaults to the full resolution level.
+    #[arg(short, long = "level")]
+    pub level: Option<u32>,
+
+    /// Image width, for pyramids without a descriptor
+    #[arg(long = "width")]
+    pub width: Option<u32>,
+
+    /// Image height, for pyramids without a descriptor
+    #[arg(long = "height")]
+    pub height: Option<u32>,
+
+    /// Tile size, for pyramids without a descriptor. Defaults to 256.
+    #[arg(short, long = "tile-size")]
+    pub tile_size: Option<u32>,
+
+    /// Overlap, for pyramids without a descriptor. Defaults to 0.
+    #[arg(long = "overlap")]
+    pub overlap: Option<u32>,
+
+    /// The PNG file to write the level to
+    #[arg(long = "out", default_value = "level.png")]
+    pub output_file: String,
+
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(propagate_version = true)]
+pub struct ServeArgs {
+
+    /// Path to source image. Leave it out and give the bitmask-mode options instead to serve their merged image.
+    #[arg(short, long = "input-image", conflicts_with = "ChannelArgs")]
+    pub input_image: Option<String>,
+
+    #[command(flatten)]
+    pub channels: Option<ChannelArgs>,
+
+    /// The file name stem tiles and the descriptor are served under
+    #[arg(short = 's', long = "output-file-stem", default_value = "dzi")]
+    pub output_file_stem: String,
+
+    /// DZI tile size
+    #[arg(short, long = "tile-size", default_value = "256")]
+    pub tile_size: u32,
+
+    /// Pixels each tile shares with its neighbours
+    #[arg(long = "overlap", default_value = "0")]
+    pub overlap: u32,
+
+    /// How each level is scaled down from the one above
+    #[arg(long = "filter", value_enum, default_value = "bilinear")]
+    pub filter: ReductionFilter,
+
+    /// How the bitmask channels of a merged image are scaled down
+    #[arg(long = "bitmask-filter", value_enum, default_value = "or")]
+    pub bitmask_filter: ReductionFilter,
+
+    /// The tile format the descriptor names. Tiles are encoded in whichever format their URL asks for.
+    #[arg(long = "format", value_enum, default_value = "png")]
+    pub format: TileFormat,
+
+    /// JPEG and WebP quality, from 0 to 100
+    #[arg(long = "quality", default_value = "90")]
+    pub quality: u8,
+
+    /// URL scheme: a DZI descriptor and `_files` tree, or XYZ or TMS map tiles
+    #[arg(long = "layout", value_enum, default_value = "dzi")]
+    pub layout: DZILayout,
+
+    /// Port to listen on, on localhost only
+    #[arg(short, long = "port", default_value = "8080")]
+    pub port: u16,
+
+    /// Folder tiles are cached in once they have been made. It is emptied when the server starts.
+    #[arg(long = "cache-folder", default_value = "tile-cache")]
+    pub cache_folder: String,
+
+    /// Most megabytes the cached tiles may take up, after which the oldest are deleted
+    #[arg(long = "cache-size", default_value = "1024")]
+    pub cache_size: u64,
+
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+use std::time::Instant;
+
+use crate::app;
+use crate::bitmask_mode::{merge_channels, CollapseMode};
+use crate::dzi_pyramid::{ChannelFilters, ReductionFilter};
+use crate::dzi_source::ImageRows;
+use crate::dzi_split_mode::tile_image;
+use crate::dzi_viewer::find_viewer_channels;
+
+/// # Bitmask DZI mode
+/// Merges the source images as bitmask-mode does, and tiles the merged image straight from memory as dzi-split-mode
+/// does, without writing it out as a PNG and reading it back in between.
+pub fn do_bitmask_dzi_mode(cli: app::BitmaskDZIModeArgs) {
+    println!("Doing bitmask DZI mode...");
+    let start_time = Instant::now();
+
+    let filters = get_channel_filters(&cli.channels, cli.filter, cli.bitmask_filter);
+
+    // A Zarr store keeps the three channels apart, each named after the image it came from
+    let get_label = |path: &str| {
+        let stem = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string());
+        return stem.unwrap_or_else(|| path.to_string());
+    };
+    let channel_labels = vec![
+        get_label(&cli.channels.red_channel_file_path),
+        get_label(&cli.channels.green_channel_file_path),
+        get_label(&cli.channels.blue_channel_file_path),
+    ];
+
+    let modes = [
+        cli.channels.red_mode.clone(),
+        cli.channels.green_mode.clone(),
+        cli.channels.blue_mode.clone(),
+    ];
+
+    let Some(combined_image) = merge_channels(cli.channels, cli.dry_run) else {
+        return;
+    };
+    println!("Images merged in {:?}", start_time.elapsed());
+
+    // The viewer gets a toggle for each class that is actually in the image
+    let viewer_channels = if cli.emit_viewer {
+        find_viewer_channels(&combined_image, channel_labels.iter().cloned().zip(modes).collect())
+    } else {
+        Vec::new()
+    };
+
+    // The descriptor names one format, so auto decides it for the whole image
+    let format = cli.format.resolve(combined_image.data.iter().all(|pixel| pixel.a == 255));
+
+    // The merged image has no file, so there is nothing to resume from
+    let tiling = app::DZISplitModeArgs {
+        input_image: String::new(),
+        output_file_stem: cli.output_file_stem,
+        output_folder: cli.output_folder,
+        tile_size: cli.tile_size,
+        overlap: cli.overlap,
+        layer_to_prepare: 0,
+        min_level: None,
+        max_level: None,
+        buffer_rows: cli.buffer_rows,
+        jobs: cli.jobs,
+        filter: cli.filter,
+        format,
+        quality: cli.quality,
+        layout: cli.layout,
+        channel_labels,
+        base_url: cli.base_url,
+        resume: false,
+        archive: cli.archive,
+        skip_empty: cli.skip_empty,
+        dedup: cli.dedup,
+        roi: cli.roi,
+        mask: cli.mask,
+        emit_viewer: cli.emit_viewer,
+    };
+    tile_image(Box::new(ImageRows::new(combined_image)), tiling, filters, viewer_channels);
+}
+
+// Averaging bitmask values makes up classes that were never there, so bitmask channels get their own filter
+pub fn get_channel_filters(channels: &app::ChannelArgs, filter: ReductionFilter, bitmask_filter: ReductionFilter) -> ChannelFilters {
+    let get_filter = |mode: &CollapseMode| match mode {
+        CollapseMode::Bitmask => bitmask_filter,
+        _ => filter,
+    };
+    return ChannelFilters {
+        r: get_filter(&channels.red_mode),
+        g: get_filter(&channels.green_mode),
+        b: get_filter(&channels.blue_mode),
+        a: filter,
+    };
+}
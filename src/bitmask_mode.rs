@@ -93,8 +93,24 @@ pub struct ImgSize(u32, u32);
 pub struct ImgScale(f32, f32);
 
 pub fn do_bitmask_mode(cli: app::BitmaskModeArgs) {
+    let Some(combined_image) = merge_channels(cli.channels, cli.dry_run) else {
+        return;
+    };
+
+    println!("Saving image...");
+    // Save dat shit
+    combined_image
+        .save(ril::ImageFormat::Png, cli.output_file)
+        .expect("could not save image");
+    println!("....and done!");
+}
 
-    let dry_run = cli.dry_run;
+/// # Merge channels
+/// Given the source images, their bounding boxes and collapse modes, return the combined image, with each source
+/// collapsed into its own channel and fitted onto the slide at the scale of the least downscaled source.
+///
+/// On a dry run, only the sizes are worked out and nothing is returned.
+pub fn merge_channels(cli: app::ChannelArgs, dry_run: bool) -> Option<Image<ril::Rgba>> {
 
     if dry_run {
         println!("Dry run enabled! No files will be saved.");
@@ -219,7 +235,7 @@ pub fn do_bitmask_mode(cli: app::BitmaskModeArgs) {
 
     if dry_run {
         println!("Dry run complete.");
-        return;
+        return None;
     }
 
     // Create blank image to paste onto
@@ -352,12 +368,7 @@ pub fn do_bitmask_mode(cli: app::BitmaskModeArgs) {
     });
     println!("Pixel data combined.");
 
-    println!("Saving image...");
-    // Save dat shit
-    combined_image
-        .save(ril::ImageFormat::Png, cli.output_file)
-        .expect("could not save image");
-    println!("....and done!");
+    return Some(combined_image);
 }
 
 /// # Get minimum downscale
@@ -365,13 +376,13 @@ pub fn do_bitmask_mode(cli: app::BitmaskModeArgs) {
 ///
 /// This function is used to figure out which image has been down-scaled the least, which is used as the basis for resizing other images.
 fn get_minimum_downscale(offsets: &ImageOffsets) -> Result<ImgScale, std::io::Error> {
-    let x_scales = vec![
+    let x_scales = [
         offsets.red.scale.0,
         offsets.green.scale.0,
         offsets.blue.scale.0,
     ];
 
-    let y_scales = vec![
+    let y_scales = [
         offsets.red.scale.1,
         offsets.green.scale.1,
         offsets.blue.scale.1,
@@ -412,7 +423,7 @@ fn bit_ize(n: u8) -> u8 {
     if n == 2 {
         return 2;
     }
-    return (2 as u8).pow((n - 1) as u32);
+    return 2_u8.pow((n - 1) as u32);
 }
 
 /// # Collapse grey to color
@@ -527,8 +538,8 @@ fn calculate_img_offset(img_height: u32, img_width: u32, img_bbox: BBox) -> Imag
     let true_width = img_bbox.max_x - img_bbox.min_x;
     let true_height = img_bbox.max_y - img_bbox.min_y;
 
-    let downscale_x: f32 = (true_width as f32 / img_width as f32) as f32;
-    let downscale_y: f32 = (true_height as f32 / img_height as f32) as f32;
+    let downscale_x: f32 = true_width as f32 / img_width as f32;
+    let downscale_y: f32 = true_height as f32 / img_height as f32;
 
     let scaled_bbox_x = ((img_bbox.min_x as f32) / downscale_x).round() as u32;
     let scaled_bbox_y = ((img_bbox.min_y as f32) / downscale_y).round() as u32;
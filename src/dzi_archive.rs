@@ -0,0 +1,463 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use rusqlite::{params, Connection};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use ril::{Image, Rgba};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::dzi_layout::{get_level_name, get_lowest_level, DZILayout, DZIOutput};
+use crate::dzi_pyramid::{get_level_dimensions, get_level_tile_count, DZIDimensions};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    /// A zip with every entry stored uncompressed, as in SZI
+    Zip,
+    Tar,
+    /// A SQLite database of tiles keyed by level, column and row as numbered in the output layout
+    Sqlite,
+    /// An MBTiles database, for the xyz and tms layouts
+    Mbtiles,
+    /// A pyramidal tiled TIFF, with deflate-compressed RGBA tiles and one reduced resolution image per level
+    Tiff,
+}
+
+impl ValueEnum for ArchiveFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[ArchiveFormat::Zip, ArchiveFormat::Tar, ArchiveFormat::Sqlite, ArchiveFormat::Mbtiles, ArchiveFormat::Tiff]
+    }
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            ArchiveFormat::Zip => Some(PossibleValue::new("zip")),
+            ArchiveFormat::Tar => Some(PossibleValue::new("tar")),
+            ArchiveFormat::Sqlite => Some(PossibleValue::new("sqlite")),
+            ArchiveFormat::Mbtiles => Some(PossibleValue::new("mbtiles")),
+            ArchiveFormat::Tiff => Some(PossibleValue::new("tiff")),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "szi",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Sqlite => "sqlite",
+            ArchiveFormat::Mbtiles => "mbtiles",
+            ArchiveFormat::Tiff => "tif",
+        }
+    }
+}
+
+/// # Tile archive
+/// A single file that a whole pyramid is written into, in place of a folder of tiles.
+///
+/// In a zip or tar, every file goes in under a `{stem}/` folder at the path it would have in the output folder, so
+/// unpacking one gives the same tree as a normal run. The SQLite and MBTiles databases keep tiles by level, column and
+/// row instead, and the other files in a table of their own. The TIFF only holds tiles, as raw pixels rather than
+/// image files.
+pub trait TileArchive {
+    /// Adds a tile, with its level as numbered in the output layout
+    fn add_tile(&mut self, level: u32, x: u32, y: u32, name: &str, bytes: &[u8]);
+
+    /// Adds a file that describes the pyramid, such as the `.dzi` descriptor
+    fn add_file(&mut self, name: &str, bytes: &[u8]);
+
+    fn finish(self: Box<Self>);
+}
+
+pub fn create_archive(
+    format: ArchiveFormat,
+    path: &str,
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+) -> Box<dyn TileArchive> {
+    match format {
+        ArchiveFormat::Zip => {
+            let file = File::create(path).expect("Error creating archive");
+            return Box::new(ZipArchive {
+                writer: ZipWriter::new(BufWriter::new(file)),
+            });
+        }
+        ArchiveFormat::Tar => {
+            let file = File::create(path).expect("Error creating archive");
+            return Box::new(TarArchive {
+                builder: tar::Builder::new(BufWriter::new(file)),
+            });
+        }
+        ArchiveFormat::Sqlite | ArchiveFormat::Mbtiles => {
+            if Path::new(path).exists() {
+                fs::remove_file(path).expect("Error removing old archive");
+            }
+            let mbtiles = match format {
+                ArchiveFormat::Mbtiles => Some(get_mbtiles_settings(output, dzi_dimensions)),
+                _ => None,
+            };
+            return Box::new(SqliteArchive::create(path, mbtiles));
+        }
+        ArchiveFormat::Tiff => {
+            return Box::new(TiffArchive::create(path, output, dzi_dimensions));
+        }
+    }
+}
+
+struct ZipArchive {
+    writer: ZipWriter<BufWriter<File>>,
+}
+
+impl TileArchive for ZipArchive {
+    fn add_tile(&mut self, _level: u32, _x: u32, _y: u32, name: &str, bytes: &[u8]) {
+        self.add_file(name, bytes);
+    }
+
+    fn add_file(&mut self, name: &str, bytes: &[u8]) {
+        // Tiles are already compressed, and stored entries can be served straight out of the file
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        self.writer.start_file(name, options).expect("Error writing archive");
+        self.writer.write_all(bytes).expect("Error writing archive");
+    }
+
+    fn finish(self: Box<Self>) {
+        let mut file = self.writer.finish().expect("Error writing archive");
+        file.flush().expect("Error writing archive");
+    }
+}
+
+struct TarArchive {
+    builder: tar::Builder<BufWriter<File>>,
+}
+
+impl TileArchive for TarArchive {
+    fn add_tile(&mut self, _level: u32, _x: u32, _y: u32, name: &str, bytes: &[u8]) {
+        self.add_file(name, bytes);
+    }
+
+    fn add_file(&mut self, name: &str, bytes: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        header.set_mtime(now);
+        self.builder.append_data(&mut header, name, bytes).expect("Error writing archive");
+    }
+
+    fn finish(self: Box<Self>) {
+        let mut file = self.builder.into_inner().expect("Error writing archive");
+        file.flush().expect("Error writing archive");
+    }
+}
+
+// Tiles are looked up by zoom_level, tile_column and tile_row, and the files that describe the pyramid are kept by
+// name in a table of their own. As a plain SQLite archive, levels and rows are numbered as in the output layout, with
+// row 0 at the top. As MBTiles, rows are counted up from the bottom of the 2^z by 2^z grid, as the spec asks, and the
+// metadata table gets the keys MBTiles readers need.
+struct SqliteArchive {
+    connection: Connection,
+    mbtiles: Option<MbtilesSettings>,
+    // The coarsest and finest level names that got tiles
+    zoom_range: Option<(u32, u32)>,
+}
+
+// What the MBTiles metadata says about the pyramid, besides its zoom range
+struct MbtilesSettings {
+    name: String,
+    format: &'static str,
+    // The image as left, bottom, right and top longitude and latitude, at max_zoom
+    bounds: [f64; 4],
+}
+
+// The image sits in the top left corner of the map tile grid, as the map layouts lay it out, so its bounds are where
+// its right and bottom edges land in Web Mercator at the finest level
+fn get_mbtiles_settings(output: &DZIOutput, dzi_dimensions: &DZIDimensions) -> MbtilesSettings {
+    if !matches!(output.layout, DZILayout::Xyz | DZILayout::Tms) {
+        panic!("MBTiles numbers its levels and rows as map tiles do, so it needs --layout xyz or tms.");
+    }
+    let max_zoom = get_level_name(&output.layout, dzi_dimensions, dzi_dimensions.max_level);
+    let world_size = output.tile_size as f64 * (1_u64 << max_zoom) as f64;
+    let get_longitude = |x: u32| x as f64 / world_size * 360.0 - 180.0;
+    let get_latitude = |y: u32| {
+        let mercator_y = std::f64::consts::PI * (1.0 - 2.0 * y as f64 / world_size);
+        mercator_y.sinh().atan().to_degrees()
+    };
+    return MbtilesSettings {
+        name: output.stem.clone(),
+        format: output.format.extension(),
+        bounds: [get_longitude(0), get_latitude(dzi_dimensions.height), get_longitude(dzi_dimensions.width), get_latitude(0)],
+    };
+}
+
+impl SqliteArchive {
+    fn create(path: &str, mbtiles: Option<MbtilesSettings>) -> Self {
+        let connection = Connection::open(path).expect("Error creating archive");
+        connection
+            .execute_batch(concat!(
+                "PRAGMA journal_mode = OFF;\n",
+                "PRAGMA synchronous = OFF;\n",
+                "CREATE TABLE metadata (name TEXT, value TEXT);\n",
+                "CREATE UNIQUE INDEX metadata_index ON metadata (name);\n",
+                "CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);\n",
+                "CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);\n",
+                "CREATE TABLE files (name TEXT PRIMARY KEY, data BLOB);\n",
+                "BEGIN;\n"
+            ))
+            .expect("Error creating archive");
+        return SqliteArchive {
+            connection,
+            mbtiles,
+            zoom_range: None,
+        };
+    }
+
+    fn add_metadata(&self, name: &str, value: &str) {
+        self.connection
+            .execute("INSERT INTO metadata (name, value) VALUES (?1, ?2)", params![name, value])
+            .expect("Error writing archive");
+    }
+}
+
+impl TileArchive for SqliteArchive {
+    fn add_tile(&mut self, level: u32, x: u32, y: u32, _name: &str, bytes: &[u8]) {
+        let row = match self.mbtiles {
+            Some(_) => (1 << level) - 1 - y,
+            None => y,
+        };
+        self.connection
+            .prepare_cached("INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)")
+            .and_then(|mut statement| statement.execute(params![level, x, row, bytes]))
+            .expect("Error writing archive");
+        self.zoom_range = Some(match self.zoom_range {
+            Some((min_zoom, max_zoom)) => (min_zoom.min(level), max_zoom.max(level)),
+            None => (level, level),
+        });
+    }
+
+    fn add_file(&mut self, name: &str, bytes: &[u8]) {
+        self.connection
+            .execute("INSERT INTO files (name, data) VALUES (?1, ?2)", params![name, bytes])
+            .expect("Error writing archive");
+    }
+
+    fn finish(self: Box<Self>) {
+        if let Some(mbtiles) = &self.mbtiles {
+            let (min_zoom, max_zoom) = self.zoom_range.unwrap_or((0, 0));
+            let bounds: Vec<String> = mbtiles.bounds.iter().map(|bound| format!("{:.6}", bound)).collect();
+            self.add_metadata("name", &mbtiles.name);
+            self.add_metadata("format", mbtiles.format);
+            self.add_metadata("bounds", &bounds.join(","));
+            self.add_metadata("minzoom", &min_zoom.to_string());
+            self.add_metadata("maxzoom", &max_zoom.to_string());
+            self.add_metadata("type", "overlay");
+        }
+        self.connection.execute_batch("COMMIT;").expect("Error writing archive");
+    }
+}
+
+/// # Encode TIFF tile
+/// Given a tile, return its pixels as the TIFF archive stores them: a full tile_size square of 8-bit RGBA, padded
+/// out with transparency on the right and bottom edges, and deflate compressed so bitmask data stays lossless.
+pub fn encode_tiff_tile(tile: &Image<Rgba>, tile_size: u32) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut row = Vec::with_capacity(tile_size as usize * 4);
+    for y in 0..tile_size {
+        row.clear();
+        for x in 0..tile_size {
+            match tile.get_pixel(x, y) {
+                Some(pixel) => row.extend([pixel.r, pixel.g, pixel.b, pixel.a]),
+                None => row.extend([0, 0, 0, 0]),
+            }
+        }
+        encoder.write_all(&row).expect("Error compressing tile");
+    }
+    return encoder.finish().expect("Error compressing tile");
+}
+
+// A pyramidal tiled TIFF: the full resolution image first, then one reduced resolution image per level below it,
+// chained in order as slide scanners and COGs do. Tiles are written as they arrive, and the image directories that
+// point at them go at the end. The file turns into a BigTIFF if it grows past what 32-bit offsets can reach.
+struct TiffArchive {
+    writer: BufWriter<File>,
+    position: u64,
+    tile_size: u32,
+    // Each level that can be written, from the full resolution one down, with its size and tile grid
+    levels: Vec<TiffLevel>,
+    // Where each tile's data went, by level name, column and row
+    tiles: HashMap<(u32, u32, u32), (u64, u64)>,
+}
+
+struct TiffLevel {
+    name: u32,
+    width: u32,
+    height: u32,
+    cols: u32,
+    rows: u32,
+}
+
+// Enough room for either header, so the choice between TIFF and BigTIFF can wait until the end
+const TIFF_HEADER_SIZE: u64 = 16;
+
+// TIFF field types
+const TIFF_SHORT: u16 = 3;
+const TIFF_LONG: u16 = 4;
+const TIFF_LONG8: u16 = 16;
+
+impl TiffArchive {
+    fn create(path: &str, output: &DZIOutput, dzi_dimensions: &DZIDimensions) -> Self {
+        if !output.tile_size.is_multiple_of(16) {
+            panic!("TIFF tiles must be a multiple of 16 pixels wide.");
+        }
+        let lowest_level = get_lowest_level(&output.layout, dzi_dimensions);
+        let levels = (lowest_level..=dzi_dimensions.max_level)
+            .rev()
+            .map(|level| {
+                let (width, height) = get_level_dimensions(dzi_dimensions, level);
+                let (cols, rows) = get_level_tile_count(dzi_dimensions, level, output.tile_size);
+                let name = get_level_name(&output.layout, dzi_dimensions, level);
+                TiffLevel { name, width, height, cols, rows }
+            })
+            .collect();
+
+        let mut writer = BufWriter::new(File::create(path).expect("Error creating archive"));
+        writer.write_all(&[0; TIFF_HEADER_SIZE as usize]).expect("Error writing archive");
+        return TiffArchive {
+            writer,
+            position: TIFF_HEADER_SIZE,
+            tile_size: output.tile_size,
+            levels,
+            tiles: HashMap::new(),
+        };
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).expect("Error writing archive");
+        self.position += bytes.len() as u64;
+    }
+
+    // Writes an offset-sized number: 32 bits in a TIFF, 64 in a BigTIFF
+    fn write_offset(&mut self, value: u64, is_big: bool) {
+        match is_big {
+            true => self.write(&value.to_le_bytes()),
+            false => self.write(&(value as u32).to_le_bytes()),
+        }
+    }
+}
+
+impl TileArchive for TiffArchive {
+    fn add_tile(&mut self, level: u32, x: u32, y: u32, _name: &str, bytes: &[u8]) {
+        self.tiles.insert((level, x, y), (self.position, bytes.len() as u64));
+        self.write(bytes);
+    }
+
+    // The TIFF tags describe the pyramid, so descriptors have nowhere to go
+    fn add_file(&mut self, _name: &str, _bytes: &[u8]) {}
+
+    fn finish(mut self: Box<Self>) {
+        // Only levels that got tiles are written, so a run limited to some levels gives a TIFF of just those
+        let levels: Vec<TiffLevel> = std::mem::take(&mut self.levels)
+            .into_iter()
+            .filter(|level| self.tiles.keys().any(|&(name, _, _)| name == level.name))
+            .collect();
+
+        // Each image directory holds 13 entries, after its tile offsets and byte counts. This is the most it can take.
+        let directories_size: u64 = levels
+            .iter()
+            .map(|level| 16 * (level.cols * level.rows) as u64 + 8 + 1 + 8 + 13 * 20 + 8)
+            .sum();
+        let is_big = self.position + directories_size > u32::MAX as u64;
+
+        let mut next_directory_pointer = if is_big { 8 } else { 4 };
+        let mut pointers = Vec::new();
+        for (index, level) in levels.iter().enumerate() {
+            let tile_locations: Vec<(u64, u64)> = (0..level.rows)
+                .flat_map(|y| (0..level.cols).map(move |x| (x, y)))
+                .map(|(x, y)| self.tiles.get(&(level.name, x, y)).copied().unwrap_or((0, 0)))
+                .collect();
+
+            // Values that don't fit in their directory entry go before it: the tile offsets and byte counts, unless
+            // there is only one tile, and in a classic TIFF the four BitsPerSample shorts
+            let offsets_position = self.position;
+            for &(offset, _) in &tile_locations {
+                self.write_offset(offset, is_big);
+            }
+            let counts_position = self.position;
+            for &(_, count) in &tile_locations {
+                self.write_offset(count, is_big);
+            }
+            let bits_per_sample = if is_big {
+                0x0008_0008_0008_0008
+            } else {
+                let bits_per_sample_position = self.position;
+                self.write(&[8, 0, 8, 0, 8, 0, 8, 0]);
+                bits_per_sample_position
+            };
+            let (offsets, counts) = match tile_locations[..] {
+                [(offset, count)] => (offset, count),
+                _ => (offsets_position, counts_position),
+            };
+            let tile_count = tile_locations.len() as u64;
+            let offset_type = if is_big { TIFF_LONG8 } else { TIFF_LONG };
+
+            let entries: [(u16, u16, u64, u64); 13] = [
+                // NewSubfileType: every image after the first is a reduced resolution copy
+                (254, TIFF_LONG, 1, if index == 0 { 0 } else { 1 }),
+                (256, TIFF_LONG, 1, level.width as u64),
+                (257, TIFF_LONG, 1, level.height as u64),
+                (258, TIFF_SHORT, 4, bits_per_sample),
+                // Compression: deflate
+                (259, TIFF_SHORT, 1, 8),
+                // PhotometricInterpretation: RGB
+                (262, TIFF_SHORT, 1, 2),
+                (277, TIFF_SHORT, 1, 4),
+                // PlanarConfiguration: chunky
+                (284, TIFF_SHORT, 1, 1),
+                (322, TIFF_LONG, 1, self.tile_size as u64),
+                (323, TIFF_LONG, 1, self.tile_size as u64),
+                (324, offset_type, tile_count, offsets),
+                (325, offset_type, tile_count, counts),
+                // ExtraSamples: unassociated alpha
+                (338, TIFF_SHORT, 1, 2),
+            ];
+
+            // Directories start on a word boundary
+            if self.position % 2 == 1 {
+                self.write(&[0]);
+            }
+            pointers.push((next_directory_pointer, self.position));
+            match is_big {
+                true => self.write(&(entries.len() as u64).to_le_bytes()),
+                false => self.write(&(entries.len() as u16).to_le_bytes()),
+            }
+            for (tag, field_type, count, value) in entries {
+                self.write(&tag.to_le_bytes());
+                self.write(&field_type.to_le_bytes());
+                self.write_offset(count, is_big);
+                self.write_offset(value, is_big);
+            }
+            next_directory_pointer = self.position;
+            self.write_offset(0, is_big);
+        }
+        self.writer.flush().expect("Error writing archive");
+
+        // The header, and each directory's pointer to the next, can only be filled in now
+        let mut file = self.writer.into_inner().expect("Error writing archive");
+        let header: Vec<u8> = match is_big {
+            true => [b"II".as_slice(), &43_u16.to_le_bytes(), &8_u16.to_le_bytes(), &0_u16.to_le_bytes()].concat(),
+            false => [b"II".as_slice(), &42_u16.to_le_bytes()].concat(),
+        };
+        file.seek(SeekFrom::Start(0)).expect("Error writing archive");
+        file.write_all(&header).expect("Error writing archive");
+        for (pointer_position, directory_position) in pointers {
+            file.seek(SeekFrom::Start(pointer_position)).expect("Error writing archive");
+            match is_big {
+                true => file.write_all(&directory_position.to_le_bytes()),
+                false => file.write_all(&(directory_position as u32).to_le_bytes()),
+            }
+            .expect("Error writing archive");
+        }
+        file.flush().expect("Error writing archive");
+    }
+}
@@ -0,0 +1,130 @@
+use std::ops::RangeInclusive;
+
+use crate::dzi_pyramid::{get_level_tile_count, DZIDimensions};
+use crate::dzi_source::open_row_source;
+
+/// # Tile footprint
+/// The tiles of every level that are worth generating: those that intersect a region of interest or the non-zero
+/// pixels of a mask. It is worked out on the tile grid of the full resolution level, and each coarser tile is kept
+/// if any of the four tiles it is scaled down from is kept.
+pub struct TileFootprint {
+    // For each level, its number of tile columns and whether each tile is kept, row after row
+    levels: Vec<(u32, Vec<bool>)>,
+}
+
+impl TileFootprint {
+    /// The tiles intersecting a rectangle given as x, y, width and height in full resolution pixels
+    pub fn from_roi(dzi_dimensions: &DZIDimensions, tile_size: u32, roi: &[u32]) -> Self {
+        let [x, y, width, height] = roi else {
+            panic!("The region of interest needs an x, y, width and height.");
+        };
+        if *width == 0 || *height == 0 || x + width > dzi_dimensions.width || y + height > dzi_dimensions.height {
+            panic!("The region of interest must be inside the image, and not empty.");
+        }
+
+        let (cols, rows) = get_level_tile_count(dzi_dimensions, dzi_dimensions.max_level, tile_size);
+        let mut tiles = vec![false; (cols * rows) as usize];
+        for tile_y in (y / tile_size)..=((y + height - 1) / tile_size) {
+            for tile_x in (x / tile_size)..=((x + width - 1) / tile_size) {
+                tiles[(tile_y * cols + tile_x) as usize] = true;
+            }
+        }
+        return TileFootprint::from_full_resolution(dzi_dimensions, tile_size, tiles);
+    }
+
+    /// The tiles intersecting any pixel of the mask that is neither black nor transparent. The mask is stretched
+    /// over the whole image, so it can be a thumbnail of it.
+    pub fn from_mask(dzi_dimensions: &DZIDimensions, tile_size: u32, mask_path: &str) -> Self {
+        let mut mask = open_row_source(mask_path);
+        let (mask_width, mask_height) = (mask.width(), mask.height());
+
+        // The tile columns and rows each mask column and row lands on
+        let get_tile_range = |mask_index: u32, mask_length: u32, image_length: u32| {
+            let scale = |index: u32| (index as u64 * image_length as u64 / mask_length as u64) as u32;
+            let start = scale(mask_index);
+            let end = scale(mask_index + 1).max(start + 1);
+            return (start / tile_size)..=((end - 1) / tile_size);
+        };
+        let column_ranges: Vec<_> = (0..mask_width)
+            .map(|mask_x| get_tile_range(mask_x, mask_width, dzi_dimensions.width))
+            .collect();
+
+        let (cols, rows) = get_level_tile_count(dzi_dimensions, dzi_dimensions.max_level, tile_size);
+        let mut tiles = vec![false; (cols * rows) as usize];
+        let mut mask_y = 0;
+        let mut pixels = Vec::new();
+        loop {
+            pixels.clear();
+            let rows_read = mask.read_rows(64, &mut pixels);
+            if rows_read == 0 {
+                break;
+            }
+            for (row, mask_row) in pixels.chunks(mask_width as usize).enumerate() {
+                let tile_rows = get_tile_range(mask_y + row as u32, mask_height, dzi_dimensions.height);
+                for (mask_x, pixel) in mask_row.iter().enumerate() {
+                    if pixel.a == 0 || (pixel.r == 0 && pixel.g == 0 && pixel.b == 0) {
+                        continue;
+                    }
+                    for tile_y in tile_rows.clone() {
+                        for tile_x in column_ranges[mask_x].clone() {
+                            tiles[(tile_y * cols + tile_x) as usize] = true;
+                        }
+                    }
+                }
+            }
+            mask_y += rows_read;
+        }
+        return TileFootprint::from_full_resolution(dzi_dimensions, tile_size, tiles);
+    }
+
+    // Scales the kept tiles of the full resolution level down through every level below it
+    fn from_full_resolution(dzi_dimensions: &DZIDimensions, tile_size: u32, tiles: Vec<bool>) -> Self {
+        let (cols, _) = get_level_tile_count(dzi_dimensions, dzi_dimensions.max_level, tile_size);
+        let mut levels = vec![(cols, tiles)];
+        for level in (0..dzi_dimensions.max_level).rev() {
+            let (finer_cols, finer_tiles) = levels.last().expect("The full resolution level is always there");
+            let finer_rows = finer_tiles.len() as u32 / finer_cols;
+            let (cols, rows) = get_level_tile_count(dzi_dimensions, level, tile_size);
+            let mut tiles = vec![false; (cols * rows) as usize];
+            for y in 0..finer_rows {
+                for x in 0..*finer_cols {
+                    if finer_tiles[(y * finer_cols + x) as usize] {
+                        tiles[(y / 2 * cols + x / 2) as usize] = true;
+                    }
+                }
+            }
+            levels.push((cols, tiles));
+        }
+        levels.reverse();
+        return TileFootprint { levels };
+    }
+
+    /// Keeps only the tiles that are in both footprints
+    pub fn intersect(mut self, other: &TileFootprint) -> Self {
+        for ((_, tiles), (_, other_tiles)) in self.levels.iter_mut().zip(&other.levels) {
+            for (tile, other_tile) in tiles.iter_mut().zip(other_tiles) {
+                *tile = *tile && *other_tile;
+            }
+        }
+        return self;
+    }
+
+    pub fn contains(&self, level: u32, x: u32, y: u32) -> bool {
+        let (cols, tiles) = &self.levels[level as usize];
+        return tiles[(y * cols + x) as usize];
+    }
+
+    /// Whether any tile in a range of tile rows of a level is kept
+    pub fn has_tiles_in_rows(&self, level: u32, rows: RangeInclusive<u32>) -> bool {
+        let (cols, tiles) = &self.levels[level as usize];
+        let from = (rows.start() * cols) as usize;
+        let to = (((rows.end() + 1) * cols) as usize).min(tiles.len());
+        return tiles[from.min(to)..to].iter().any(|&tile| tile);
+    }
+
+    /// How many tiles are kept at the full resolution level, and how many there are in all
+    pub fn count_full_resolution(&self) -> (usize, usize) {
+        let (_, tiles) = self.levels.last().expect("The full resolution level is always there");
+        return (tiles.iter().filter(|&&tile| tile).count(), tiles.len());
+    }
+}
@@ -0,0 +1,453 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::dzi_pyramid::{get_level_dimensions, get_level_tile_count, get_tile_bounds, DZIDimensions};
+use crate::dzi_zarr::{get_zarr_array_name, get_zarr_metadata, get_zarr_root};
+use crate::tile_format::TileFormat;
+
+#[derive(Clone)]
+pub enum DZILayout {
+    /// `{stem}.dzi` plus `{stem}_files/{level}/{col}_{row}.png`
+    Dzi,
+    /// Flat `{stem}_{z}_{y}_{x}.png` files, where z = 0 is the single-tile level
+    Legacy,
+    /// `{stem}/{z}/{x}/{y}.png` map tiles with row 0 at the top, where z = 0 is the single-tile level
+    Xyz,
+    /// The same as XYZ, but with row 0 at the bottom of a 2^z by 2^z grid
+    Tms,
+    /// IIIF Image API level 0 static tiles, `{stem}/{region}/{size}/0/default.png` plus `{stem}/info.json`
+    Iiif,
+    /// Zoomify `{stem}/TileGroup{N}/{z}-{x}-{y}.png` with 256 tiles per group, plus `{stem}/ImageProperties.xml`
+    Zoomify,
+    /// An OME-NGFF `{stem}.zarr` store with one array per level, holding raw channels rather than image files
+    Zarr,
+}
+
+impl ValueEnum for DZILayout {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[DZILayout::Dzi, DZILayout::Legacy, DZILayout::Xyz, DZILayout::Tms, DZILayout::Iiif, DZILayout::Zoomify, DZILayout::Zarr]
+    }
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            DZILayout::Dzi => Some(PossibleValue::new("dzi")),
+            DZILayout::Legacy => Some(PossibleValue::new("legacy")),
+            DZILayout::Xyz => Some(PossibleValue::new("xyz")),
+            DZILayout::Tms => Some(PossibleValue::new("tms")),
+            DZILayout::Iiif => Some(PossibleValue::new("iiif")),
+            DZILayout::Zoomify => Some(PossibleValue::new("zoomify")),
+            DZILayout::Zarr => Some(PossibleValue::new("zarr")),
+        }
+    }
+}
+
+// Where, and under which naming scheme, the tiles are written
+pub struct DZIOutput {
+    pub layout: DZILayout,
+    pub folder: String,
+    pub stem: String,
+    pub format: TileFormat,
+    // JPEG and WebP quality, 0 to 100
+    pub quality: u8,
+    pub tile_size: u32,
+    // The URL the output folder is served from, for descriptors that have to name it
+    pub base_url: String,
+    // Whether everything goes into one archive file instead of the folder tree
+    pub is_archived: bool,
+    // The name of each channel, for layouts that store channels apart. Only as many channels as names are kept.
+    pub channel_labels: Vec<String>,
+}
+
+// The coarsest level a layout has. DZI carries on down to 1x1 px, the other layouts stop at the single-tile level.
+// Legacy counts its levels as it always has, which can leave its coarsest level a few pixels over one tile.
+pub fn get_lowest_level(layout: &DZILayout, dzi_dimensions: &DZIDimensions) -> u32 {
+    match layout {
+        DZILayout::Dzi => 0,
+        DZILayout::Legacy => dzi_dimensions.max_level - dzi_dimensions.zoom_levels,
+        _ => dzi_dimensions.max_level - dzi_dimensions.single_tile_levels,
+    }
+}
+
+// The level number as it appears in the output for the given layout
+pub fn get_level_name(layout: &DZILayout, dzi_dimensions: &DZIDimensions, level: u32) -> u32 {
+    return level - get_lowest_level(layout, dzi_dimensions);
+}
+
+// The DZI level for a level number as it appears in the output
+pub fn get_level_from_name(layout: &DZILayout, dzi_dimensions: &DZIDimensions, name: u32) -> u32 {
+    return name + get_lowest_level(layout, dzi_dimensions);
+}
+
+fn get_file_name(x: u32, y: u32, z: u32, prefix: &str) -> String {
+    if prefix.is_empty() {
+        return format!("{}_{}_{}", z, y, x);
+    }
+    return format!("{}_{}_{}_{}", prefix, z, y, x);
+}
+
+// The folder a tile goes in. Legacy tiles all live in the output folder.
+pub fn get_tile_folder(output: &DZIOutput, dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32) -> String {
+    let level_name = get_level_name(&output.layout, dzi_dimensions, level);
+    match output.layout {
+        DZILayout::Dzi => format!("{}/{}_files/{}", output.folder, output.stem, level_name),
+        DZILayout::Legacy => output.folder.clone(),
+        DZILayout::Xyz | DZILayout::Tms => format!("{}/{}/{}/{}", output.folder, output.stem, level_name, x),
+        DZILayout::Iiif => {
+            let (region, size) = get_iiif_region_and_size(output, dzi_dimensions, level, x, y);
+            format!("{}/{}/{}/{}/0", output.folder, output.stem, region, size)
+        }
+        DZILayout::Zoomify => {
+            let tile_group = get_zoomify_tile_index(output, dzi_dimensions, level, x, y) / 256;
+            format!("{}/{}/TileGroup{}", output.folder, output.stem, tile_group)
+        }
+        // Chunks are keyed by their channel, row and column, and every chunk holds all the channels
+        DZILayout::Zarr => {
+            format!("{}/{}/0/{}", get_zarr_root(output), get_zarr_array_name(dzi_dimensions, level), y)
+        }
+    }
+}
+
+pub fn get_tile_path(output: &DZIOutput, dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32, extension: &str) -> String {
+    let tile_folder = get_tile_folder(output, dzi_dimensions, level, x, y);
+    let z = get_level_name(&output.layout, dzi_dimensions, level);
+    match output.layout {
+        DZILayout::Dzi => format!("{}/{}_{}.{}", tile_folder, x, y, extension),
+        DZILayout::Legacy => format!("{}/{}.{}", tile_folder, get_file_name(x, y, z, &output.stem), extension),
+        DZILayout::Xyz => format!("{}/{}.{}", tile_folder, y, extension),
+        DZILayout::Tms => format!("{}/{}.{}", tile_folder, (1 << z) - 1 - y, extension),
+        DZILayout::Iiif => format!("{}/default.{}", tile_folder, extension),
+        DZILayout::Zoomify => format!("{}/{}-{}-{}.{}", tile_folder, z, x, y, extension),
+        DZILayout::Zarr => format!("{}/{}", tile_folder, x),
+    }
+}
+
+// Zoomify numbers every tile in the pyramid, from the single-tile level down and row by row within a level
+fn get_zoomify_tile_index(output: &DZIOutput, dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32) -> u32 {
+    let lowest_level = dzi_dimensions.max_level - dzi_dimensions.single_tile_levels;
+    let tiles_before_level: u32 = (lowest_level..level)
+        .map(|coarser_level| {
+            let (cols, rows) = get_level_tile_count(dzi_dimensions, coarser_level, output.tile_size);
+            cols * rows
+        })
+        .sum();
+    let (cols, _) = get_level_tile_count(dzi_dimensions, level, output.tile_size);
+    return tiles_before_level + y * cols + x;
+}
+
+/// # IIIF region and size
+/// Given a tile, return the region of the full image it covers and the size it is scaled to, as they appear in IIIF
+/// Image API URLs.
+///
+/// A level 0 server can only answer the exact requests a viewer makes, so these follow OpenSeadragon: a level that
+/// fits in a single tile is asked for as the `full` region, and only the full image at full size as `max`.
+fn get_iiif_region_and_size(output: &DZIOutput, dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32) -> (String, String) {
+    let scale_factor = 1 << (dzi_dimensions.max_level - level);
+    let (level_width, level_height) = get_level_dimensions(dzi_dimensions, level);
+    let bounds = get_tile_bounds(dzi_dimensions, level, x, y, output.tile_size);
+
+    let size = if bounds.width == dzi_dimensions.width && bounds.height == dzi_dimensions.height {
+        "max".to_string()
+    } else {
+        format!("{},{}", bounds.width, bounds.height)
+    };
+    if level_width < output.tile_size && level_height < output.tile_size {
+        return ("full".to_string(), size);
+    }
+
+    let region_x = bounds.x * scale_factor;
+    let region_y = bounds.y * scale_factor;
+    let region_width = (bounds.width * scale_factor).min(dzi_dimensions.width - region_x);
+    let region_height = (bounds.height * scale_factor).min(dzi_dimensions.height - region_y);
+    return (format!("{},{},{},{}", region_x, region_y, region_width, region_height), size);
+}
+
+// Looks for a tile that has already been written, under the output format's extension first and then the others,
+// since a level read back from disk may have been written with another format
+pub fn find_tile_path(output: &DZIOutput, dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32) -> Option<String> {
+    let first_extension = output.format.extension();
+    let other_extensions = ["png", "jpg", "webp"].into_iter().filter(|&extension| extension != first_extension);
+    return std::iter::once(first_extension)
+        .chain(other_extensions)
+        .map(|extension| get_tile_path(output, dzi_dimensions, level, x, y, extension))
+        .find(|path| Path::new(path).exists());
+}
+
+// Where a file from the output folder goes inside an archive
+pub fn get_archive_entry_name(output: &DZIOutput, path: &str) -> String {
+    return format!("{}/{}", output.stem, get_relative_path(output, path));
+}
+
+// A path inside the output folder, relative to it
+pub fn get_relative_path<'a>(output: &DZIOutput, path: &'a str) -> &'a str {
+    return path
+        .strip_prefix(&format!("{}/", output.folder))
+        .expect("Output files are always inside the output folder");
+}
+
+// Where the layout keeps the file that describes the pyramid. Legacy output has none.
+pub fn get_descriptor_path(output: &DZIOutput) -> Option<String> {
+    match output.layout {
+        DZILayout::Dzi => Some(format!("{}/{}.dzi", output.folder, output.stem)),
+        DZILayout::Legacy => None,
+        DZILayout::Xyz | DZILayout::Tms => Some(format!("{}/{}.json", output.folder, output.stem)),
+        DZILayout::Iiif => Some(format!("{}/{}/info.json", output.folder, output.stem)),
+        DZILayout::Zoomify => Some(format!("{}/{}/ImageProperties.xml", output.folder, output.stem)),
+        DZILayout::Zarr => Some(format!("{}/.zattrs", get_zarr_root(output))),
+    }
+}
+
+// The files that describe the pyramid in the output layout, as their paths and contents. Most layouts have one,
+// Zarr has its group attributes plus the metadata of every array.
+pub fn get_descriptor_files(
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+    tile_size: u32,
+    extension: &str,
+) -> Vec<(String, String)> {
+    let Some(descriptor_path) = get_descriptor_path(output) else {
+        return Vec::new();
+    };
+    let descriptor = match output.layout {
+        DZILayout::Dzi => get_dzi_descriptor(dzi_dimensions, tile_size, extension),
+        DZILayout::Legacy => return Vec::new(),
+        DZILayout::Xyz | DZILayout::Tms => get_map_descriptor(output, dzi_dimensions, tile_size, extension),
+        DZILayout::Iiif => get_iiif_descriptor(output, dzi_dimensions, tile_size, extension),
+        DZILayout::Zoomify => get_zoomify_descriptor(dzi_dimensions, tile_size),
+        DZILayout::Zarr => return get_zarr_metadata(output, dzi_dimensions, tile_size),
+    };
+    return vec![(descriptor_path, descriptor)];
+}
+
+pub fn write_descriptor(output: &DZIOutput, dzi_dimensions: &DZIDimensions, tile_size: u32, extension: &str) {
+    for (descriptor_path, descriptor) in get_descriptor_files(output, dzi_dimensions, tile_size, extension) {
+        if let Some(descriptor_folder) = Path::new(&descriptor_path).parent() {
+            fs::create_dir_all(descriptor_folder).expect("Error creating output folder");
+        }
+        fs::write(descriptor_path, descriptor).expect("Error writing descriptor");
+    }
+}
+
+// What the descriptor of a pyramid says about it. Anything it doesn't say is None.
+#[derive(Default)]
+pub struct DescribedPyramid {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub tile_size: Option<u32>,
+    pub overlap: Option<u32>,
+    pub extension: Option<String>,
+}
+
+// The fields of the map layouts' `{stem}.json` that describe the pyramid
+#[derive(Deserialize)]
+struct MapDescriptor {
+    width: Option<u32>,
+    height: Option<u32>,
+    tile_size: Option<u32>,
+    tiles: Option<String>,
+}
+
+// The fields of a IIIF `info.json` that describe the pyramid
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IiifDescriptor {
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    tiles: Vec<IiifTiles>,
+    #[serde(default)]
+    preferred_formats: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct IiifTiles {
+    width: u32,
+}
+
+// Picks the image size, tile size, overlap and format out of a descriptor written by dzi-split-mode
+pub fn read_descriptor(layout: &DZILayout, text: &str) -> DescribedPyramid {
+    match layout {
+        DZILayout::Dzi => {
+            let document = roxmltree::Document::parse(text).expect("Error parsing DZI descriptor");
+            let image = document.root_element();
+            if image.tag_name().name() != "Image" {
+                panic!("A DZI descriptor has to have an Image element at its root.");
+            }
+            let size = image.children().find(|node| node.tag_name().name() == "Size");
+            DescribedPyramid {
+                width: size.and_then(|size| get_xml_number(&size, "Width")),
+                height: size.and_then(|size| get_xml_number(&size, "Height")),
+                tile_size: get_xml_number(&image, "TileSize"),
+                overlap: get_xml_number(&image, "Overlap"),
+                extension: image.attribute("Format").map(|format| format.to_string()),
+            }
+        }
+        DZILayout::Zoomify => {
+            let document = roxmltree::Document::parse(text).expect("Error parsing Zoomify descriptor");
+            let properties = document.root_element();
+            DescribedPyramid {
+                width: get_xml_number(&properties, "WIDTH"),
+                height: get_xml_number(&properties, "HEIGHT"),
+                tile_size: get_xml_number(&properties, "TILESIZE"),
+                overlap: None,
+                extension: None,
+            }
+        }
+        DZILayout::Xyz | DZILayout::Tms => {
+            let descriptor: MapDescriptor = serde_json::from_str(text).expect("Error parsing map descriptor");
+            DescribedPyramid {
+                width: descriptor.width,
+                height: descriptor.height,
+                tile_size: descriptor.tile_size,
+                overlap: None,
+                extension: descriptor
+                    .tiles
+                    .and_then(|template| template.rsplit_once('.').map(|(_, extension)| extension.to_string())),
+            }
+        }
+        DZILayout::Iiif => {
+            let descriptor: IiifDescriptor = serde_json::from_str(text).expect("Error parsing IIIF descriptor");
+            DescribedPyramid {
+                width: descriptor.width,
+                height: descriptor.height,
+                tile_size: descriptor.tiles.first().map(|tiles| tiles.width),
+                overlap: None,
+                // JPEG is the format a IIIF viewer assumes when none is preferred
+                extension: descriptor.preferred_formats.into_iter().next().or(Some("jpg".to_string())),
+            }
+        }
+        DZILayout::Legacy | DZILayout::Zarr => DescribedPyramid::default(),
+    }
+}
+
+// The value of an XML element's attribute, as a number
+fn get_xml_number(node: &roxmltree::Node, name: &str) -> Option<u32> {
+    return node.attribute(name).and_then(|value| value.trim().parse().ok());
+}
+
+// The `{stem}.dzi` XML descriptor
+fn get_dzi_descriptor(dzi_dimensions: &DZIDimensions, tile_size: u32, extension: &str) -> String {
+    return format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" TileSize=\"{}\" Overlap=\"{}\" Format=\"{}\">\n",
+            "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+            "</Image>\n"
+        ),
+        tile_size, dzi_dimensions.overlap, extension, dzi_dimensions.width, dzi_dimensions.height
+    );
+}
+
+// The `{stem}.json` metadata for map tile layouts. Bounds are in pixels at max_zoom, as left, top, right, bottom.
+fn get_map_descriptor(output: &DZIOutput, dzi_dimensions: &DZIDimensions, tile_size: u32, extension: &str) -> String {
+    let scheme = match output.layout {
+        DZILayout::Tms => "tms",
+        _ => "xyz",
+    };
+    return format!(
+        concat!(
+            "{{\n",
+            "  \"scheme\": \"{}\",\n",
+            "  \"tiles\": \"{}/{{z}}/{{x}}/{{y}}.{}\",\n",
+            "  \"tile_size\": {},\n",
+            "  \"min_zoom\": 0,\n",
+            "  \"max_zoom\": {},\n",
+            "  \"width\": {},\n",
+            "  \"height\": {},\n",
+            "  \"bounds\": [0, 0, {}, {}]\n",
+            "}}\n"
+        ),
+        scheme,
+        output.stem,
+        extension,
+        tile_size,
+        dzi_dimensions.single_tile_levels,
+        dzi_dimensions.width,
+        dzi_dimensions.height,
+        dzi_dimensions.width,
+        dzi_dimensions.height
+    );
+}
+
+// The IIIF Image API 3 `info.json`, listing one scale factor per level down to the single-tile level
+fn get_iiif_descriptor(output: &DZIOutput, dzi_dimensions: &DZIDimensions, tile_size: u32, extension: &str) -> String {
+    let id = if output.base_url.is_empty() {
+        output.stem.clone()
+    } else {
+        format!("{}/{}", output.base_url, output.stem)
+    };
+    let scale_factors: Vec<String> = (0..=dzi_dimensions.single_tile_levels).map(|z| (1_u32 << z).to_string()).collect();
+    // JPEG is the only format a IIIF viewer assumes, any other has to be offered
+    let formats = if extension == "jpg" {
+        String::new()
+    } else {
+        format!("  \"extraFormats\": [\"{0}\"],\n  \"preferredFormats\": [\"{0}\"],\n", extension)
+    };
+    return format!(
+        concat!(
+            "{{\n",
+            "  \"@context\": \"http://iiif.io/api/image/3/context.json\",\n",
+            "  \"id\": \"{}\",\n",
+            "  \"type\": \"ImageService3\",\n",
+            "  \"protocol\": \"http://iiif.io/api/image\",\n",
+            "  \"profile\": \"level0\",\n",
+            "  \"width\": {},\n",
+            "  \"height\": {},\n",
+            "{}",
+            "  \"tiles\": [{{ \"width\": {}, \"height\": {}, \"scaleFactors\": [{}] }}]\n",
+            "}}\n"
+        ),
+        id,
+        dzi_dimensions.width,
+        dzi_dimensions.height,
+        formats,
+        tile_size,
+        tile_size,
+        scale_factors.join(", ")
+    );
+}
+
+// The Zoomify `ImageProperties.xml`. Zoomify has no field for the format, viewers are told the extension separately.
+fn get_zoomify_descriptor(dzi_dimensions: &DZIDimensions, tile_size: u32) -> String {
+    let lowest_level = dzi_dimensions.max_level - dzi_dimensions.single_tile_levels;
+    let tile_count: u32 = (lowest_level..=dzi_dimensions.max_level)
+        .map(|level| {
+            let (cols, rows) = get_level_tile_count(dzi_dimensions, level, tile_size);
+            cols * rows
+        })
+        .sum();
+    return format!(
+        "<IMAGE_PROPERTIES WIDTH=\"{}\" HEIGHT=\"{}\" NUMTILES=\"{}\" NUMIMAGES=\"1\" VERSION=\"1.8\" TILESIZE=\"{}\" />\n",
+        dzi_dimensions.width, dzi_dimensions.height, tile_count, tile_size
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dzi_descriptor_attributes_can_come_in_any_order() {
+        let text = concat!(
+            "<?xml version='1.0'?>\n",
+            "<dz:Image xmlns:dz='http://schemas.microsoft.com/deepzoom/2008'\n",
+            "    Format = 'jpg' Overlap='1' TileSize='254'>\n",
+            "  <dz:Size Height='900' Width='1500' />\n",
+            "</dz:Image>\n"
+        );
+        let described = read_descriptor(&DZILayout::Dzi, text);
+        assert_eq!(described.width, Some(1500));
+        assert_eq!(described.height, Some(900));
+        assert_eq!(described.tile_size, Some(254));
+        assert_eq!(described.overlap, Some(1));
+        assert_eq!(described.extension.as_deref(), Some("jpg"));
+    }
+
+    #[test]
+    fn iiif_descriptor_tile_size_comes_from_its_tiles() {
+        let text = r#"{ "tiles": [{ "scaleFactors": [1, 2], "height": 512, "width": 512 }], "height": 900, "width": 1500 }"#;
+        let described = read_descriptor(&DZILayout::Iiif, text);
+        assert_eq!((described.width, described.height, described.tile_size), (Some(1500), Some(900), Some(512)));
+        assert_eq!(described.extension.as_deref(), Some("jpg"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// # Progress manifest
+/// A record of every tile and level a dzi-split-mode run has finished, kept in the output folder.
+///
+/// The manifest is a text file that is only ever appended to, one line per finished tile and one per finished level:
+///
+/// ```text
+/// settings <everything that decides what the tiles look like>
+/// tile <level> <x> <y> <extension> <size> <crc32>
+/// level <level>
+/// ```
+///
+/// A tile is only recorded once its file has been written in full, so a run that dies part way through leaves at worst
+/// a tile file with no line, which is redone. When a run resumes, a recorded tile only counts as done if its file still
+/// has the recorded size and checksum.
+pub struct ProgressManifest {
+    file: File,
+    // Tiles whose files match what the manifest says about them, with their extension
+    verified_tiles: HashMap<(u32, u32, u32), String>,
+    finished_levels: HashSet<u32>,
+}
+
+impl ProgressManifest {
+    /// Opens the manifest at `path`, or starts a new one, and checks every tile it lists.
+    /// `tile_path` gives the file a tile was written to, from its level, x, y and extension.
+    pub fn open(path: &str, settings: &str, tile_path: impl Fn(u32, u32, u32, &str) -> String) -> Self {
+        let mut verified_tiles = HashMap::new();
+        let mut finished_levels = HashSet::new();
+
+        if Path::new(path).exists() {
+            let reader = BufReader::new(File::open(path).expect("Error opening progress manifest"));
+            let mut lines = reader.lines().map(|line| line.expect("Error reading progress manifest"));
+
+            let saved_settings = lines.next().unwrap_or_default();
+            if saved_settings != format!("settings {}", settings) {
+                panic!(
+                    "The progress manifest at {} was written with different settings. Remove it, or run without --resume.",
+                    path
+                );
+            }
+
+            for line in lines {
+                let fields: Vec<&str> = line.split(' ').collect();
+                match fields.as_slice() {
+                    ["tile", level, x, y, extension, size, checksum] => {
+                        // The last line may have been cut off mid-write
+                        let (Ok(level), Ok(x), Ok(y), Ok(size), Ok(checksum)) = (
+                            level.parse::<u32>(),
+                            x.parse::<u32>(),
+                            y.parse::<u32>(),
+                            size.parse::<usize>(),
+                            u32::from_str_radix(checksum, 16),
+                        ) else {
+                            continue;
+                        };
+                        let is_intact = fs::read(tile_path(level, x, y, extension))
+                            .map(|bytes| bytes.len() == size && crc32fast::hash(&bytes) == checksum)
+                            .unwrap_or(false);
+                        if is_intact {
+                            verified_tiles.insert((level, x, y), extension.to_string());
+                        }
+                    }
+                    ["level", level] => {
+                        if let Ok(level) = level.parse::<u32>() {
+                            finished_levels.insert(level);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        } else {
+            fs::write(path, format!("settings {}\n", settings)).expect("Error writing progress manifest");
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .expect("Error opening progress manifest");
+
+        return ProgressManifest {
+            file,
+            verified_tiles,
+            finished_levels,
+        };
+    }
+
+    /// The extension of a tile that is already done, if it is
+    pub fn finished_tile(&self, level: u32, x: u32, y: u32) -> Option<&str> {
+        return self.verified_tiles.get(&(level, x, y)).map(|extension| extension.as_str());
+    }
+
+    /// Whether a level was marked finished, and all of its tiles are still intact
+    pub fn is_level_finished(&self, level: u32, cols: u32, rows: u32) -> bool {
+        return self.finished_levels.contains(&level)
+            && (0..rows).all(|y| (0..cols).all(|x| self.verified_tiles.contains_key(&(level, x, y))));
+    }
+
+    pub fn record_tile(&mut self, level: u32, x: u32, y: u32, extension: &str, bytes: &[u8]) {
+        writeln!(
+            self.file,
+            "tile {} {} {} {} {} {:08x}",
+            level,
+            x,
+            y,
+            extension,
+            bytes.len(),
+            crc32fast::hash(bytes)
+        )
+        .expect("Error writing progress manifest");
+        self.verified_tiles.insert((level, x, y), extension.to_string());
+    }
+
+    pub fn record_level(&mut self, level: u32) {
+        writeln!(self.file, "level {}", level).expect("Error writing progress manifest");
+        self.finished_levels.insert(level);
+    }
+
+    /// Makes sure everything recorded so far is on disk
+    pub fn flush(&mut self) {
+        self.file.sync_data().expect("Error writing progress manifest");
+    }
+}
@@ -0,0 +1,629 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use ril::{Image, Rgba};
+use std::ops::RangeInclusive;
+
+use crate::dzi_footprint::TileFootprint;
+
+// Rows scaled alongside each band, so the resize filter sees the same neighbourhood
+// wherever a band happens to start or end. Lanczos reaches furthest, 3 rows of the
+// scaled band (6 of the source) either side.
+const SCALE_MARGIN: u32 = 4;
+
+/// How each 2x2 block of a level becomes one pixel of the level below
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReductionFilter {
+    Bilinear,
+    Lanczos,
+    /// The mean of the four pixels
+    Box,
+    /// The top-left pixel
+    Nearest,
+    /// Bitwise OR of the four pixels, so every bitmask class in the block survives
+    Or,
+    /// The most common of the four pixels, ties going to the first in reading order
+    Mode,
+}
+
+impl ValueEnum for ReductionFilter {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            ReductionFilter::Bilinear,
+            ReductionFilter::Lanczos,
+            ReductionFilter::Box,
+            ReductionFilter::Nearest,
+            ReductionFilter::Or,
+            ReductionFilter::Mode,
+        ]
+    }
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            ReductionFilter::Bilinear => Some(PossibleValue::new("bilinear")),
+            ReductionFilter::Lanczos => Some(PossibleValue::new("lanczos")),
+            ReductionFilter::Box => Some(PossibleValue::new("box")),
+            ReductionFilter::Nearest => Some(PossibleValue::new("nearest")),
+            ReductionFilter::Or => Some(PossibleValue::new("or")),
+            ReductionFilter::Mode => Some(PossibleValue::new("mode")),
+        }
+    }
+}
+
+/// The filter each channel is scaled down with, for images whose channels hold different kinds of data, such as
+/// bitmask classes next to a heatmap
+#[derive(Clone, Copy)]
+pub struct ChannelFilters {
+    pub r: ReductionFilter,
+    pub g: ReductionFilter,
+    pub b: ReductionFilter,
+    pub a: ReductionFilter,
+}
+
+impl ChannelFilters {
+    pub fn all(filter: ReductionFilter) -> Self {
+        return ChannelFilters {
+            r: filter,
+            g: filter,
+            b: filter,
+            a: filter,
+        };
+    }
+}
+
+pub struct DZIDimensions {
+    pub width: u32,
+    pub height: u32,
+    // Number of halvings until the image fits in a single tile (the legacy level count)
+    pub zoom_levels: u32,
+    // Number of levels below the top, each rounded up as get_level_dimensions does, until one fits in a single tile
+    pub single_tile_levels: u32,
+    // The full-resolution level in DZI numbering, where level 0 is 1x1 px
+    pub max_level: u32,
+    // Extra pixels shared with each neighbouring tile
+    pub overlap: u32,
+}
+
+// The area a tile covers within its level, overlap included
+pub struct TileBounds {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// # Tile sink
+/// Somewhere to put the tiles of a pyramid as they are cut.
+///
+/// Tiles arrive one tile row at a time, left to right, and each level's rows arrive top to bottom.
+/// Rows of different levels are interleaved, because every level is built while the one above it is still being read.
+pub trait TileSink {
+    fn write_tile_row(&mut self, level: u32, y: u32, tiles: Vec<(u32, Image<Rgba>)>);
+}
+
+// A horizontal band of whole rows of a level, starting at first_row
+struct LevelStrip {
+    width: u32,
+    first_row: u32,
+    pixels: Vec<Rgba>,
+}
+
+impl LevelStrip {
+    fn end_row(&self) -> u32 {
+        return self.first_row + (self.pixels.len() / self.width as usize) as u32;
+    }
+
+    // Copies rows [start, end) out as an image
+    fn to_image(&self, start: u32, end: u32) -> Image<Rgba> {
+        let from = ((start - self.first_row) * self.width) as usize;
+        let to = ((end - self.first_row) * self.width) as usize;
+        return Image::from_pixels(self.width, &self.pixels[from..to]);
+    }
+
+    // Forgets every row before `row`
+    fn drop_rows_before(&mut self, row: u32) {
+        if row <= self.first_row {
+            return;
+        }
+        let count = (row.min(self.end_row()) - self.first_row) * self.width;
+        self.pixels.drain(..count as usize);
+        self.first_row = row;
+    }
+}
+
+struct LevelState {
+    level: u32,
+    width: u32,
+    height: u32,
+    strip: LevelStrip,
+    // The next tile row waiting for its pixels
+    next_tile_row: u32,
+    // The first row not yet scaled down into the next level
+    next_scaled_row: u32,
+}
+
+/// # Pyramid builder
+/// Builds every level of a pyramid in one pass over the rows of its top level.
+///
+/// Each level only keeps the band of rows that its next tile row and its next scaled band still need.
+/// Once a level has enough rows it cuts the tiles, hands them to the sink, and scales a band down into the level below.
+/// Nothing is ever read back from the sink, so every tile is encoded exactly once.
+/// Levels outside `levels_to_write` are still built, as they are needed for the levels below them, but their tiles are not cut.
+/// With a footprint, only the tiles in it are cut, and everything outside it is taken to be transparent at every
+/// level, so bands with no tile of the footprint in them are never scaled.
+///
+/// Peak memory grows with the image width, times `buffer_rows` tile rows per level.
+pub struct PyramidBuilder<'a, S: TileSink> {
+    dzi_dimensions: &'a DZIDimensions,
+    tile_size: u32,
+    // Rows of the lower level produced by each scaling step
+    band_rows: u32,
+    filters: ChannelFilters,
+    // Ordered from the top level down
+    levels: Vec<LevelState>,
+    levels_to_write: RangeInclusive<u32>,
+    footprint: Option<&'a TileFootprint>,
+    sink: &'a mut S,
+}
+
+impl<'a, S: TileSink> PyramidBuilder<'a, S> {
+    pub fn new(
+        dzi_dimensions: &'a DZIDimensions,
+        tile_size: u32,
+        buffer_rows: u32,
+        top_level: u32,
+        levels_to_write: RangeInclusive<u32>,
+        filters: ChannelFilters,
+        sink: &'a mut S,
+    ) -> Self {
+        let levels = (*levels_to_write.start()..=top_level)
+            .rev()
+            .map(|level| {
+                let (width, height) = get_level_dimensions(dzi_dimensions, level);
+                LevelState {
+                    level,
+                    width,
+                    height,
+                    strip: LevelStrip {
+                        width,
+                        first_row: 0,
+                        pixels: Vec::new(),
+                    },
+                    next_tile_row: 0,
+                    next_scaled_row: 0,
+                }
+            })
+            .collect();
+
+        return PyramidBuilder {
+            dzi_dimensions,
+            tile_size,
+            band_rows: buffer_rows.max(1) * tile_size,
+            filters,
+            levels,
+            levels_to_write,
+            footprint: None,
+            sink,
+        };
+    }
+
+    /// Only cuts the tiles in the footprint, and treats everything outside it as transparent
+    pub fn with_footprint(mut self, footprint: Option<&'a TileFootprint>) -> Self {
+        self.footprint = footprint;
+        return self;
+    }
+
+    /// Adds the next whole rows of the top level, and does all the work they make possible
+    pub fn push_rows(&mut self, pixels: &[Rgba]) {
+        self.push_level_rows(0, pixels);
+    }
+
+    /// Checks that the top level was read to the end, at which point every level is complete
+    pub fn finish(self) {
+        let top = &self.levels[0];
+        if top.strip.end_row() != top.height {
+            panic!(
+                "Pyramid input ended after {} of {} rows.",
+                top.strip.end_row(),
+                top.height
+            );
+        }
+    }
+
+    fn push_level_rows(&mut self, index: usize, pixels: &[Rgba]) {
+        let first_new_row = self.levels[index].strip.end_row();
+        self.levels[index].strip.pixels.extend_from_slice(pixels);
+        self.clear_outside_footprint(index, first_new_row);
+        self.write_ready_tile_rows(index);
+
+        if index + 1 < self.levels.len() {
+            while let Some(scaled_rows) = self.scale_next_band(index) {
+                self.push_level_rows(index + 1, &scaled_rows);
+            }
+        } else {
+            self.levels[index].next_scaled_row = self.levels[index].height;
+        }
+
+        self.drop_finished_rows(index);
+    }
+
+    // Makes the new rows of a level transparent wherever they fall in a tile outside the footprint, so that what is
+    // scaled down matches what a later run reads back from the tile index
+    fn clear_outside_footprint(&mut self, index: usize, first_new_row: u32) {
+        let Some(footprint) = self.footprint else {
+            return;
+        };
+        let state = &mut self.levels[index];
+        let tile_cols = state.width.div_ceil(self.tile_size);
+        let transparent = Rgba { r: 0, g: 0, b: 0, a: 0 };
+        for row in first_new_row..state.strip.end_row() {
+            let y = row / self.tile_size;
+            let row_start = ((row - state.strip.first_row) * state.width) as usize;
+            for x in (0..tile_cols).filter(|&x| !footprint.contains(state.level, x, y)) {
+                let start = row_start + (x * self.tile_size) as usize;
+                let end = row_start + ((x + 1) * self.tile_size).min(state.width) as usize;
+                state.strip.pixels[start..end].fill(transparent);
+            }
+        }
+    }
+
+    // Cuts and hands over every tile row whose pixels, overlap included, have all arrived
+    fn write_ready_tile_rows(&mut self, index: usize) {
+        let state = &mut self.levels[index];
+        let tile_size = self.tile_size;
+        let tile_rows = state.height.div_ceil(tile_size);
+        let tile_cols = state.width.div_ceil(tile_size);
+        if !self.levels_to_write.contains(&state.level) {
+            state.next_tile_row = tile_rows;
+        }
+
+        while state.next_tile_row < tile_rows {
+            let y = state.next_tile_row;
+            let needed_end = ((y + 1) * tile_size + self.dzi_dimensions.overlap).min(state.height);
+            if state.strip.end_row() < needed_end {
+                break;
+            }
+
+            let footprint = self.footprint;
+            let tiles = (0..tile_cols)
+                .filter(|&x| footprint.is_none_or(|footprint| footprint.contains(state.level, x, y)))
+                .map(|x| {
+                    let bounds = get_tile_bounds(self.dzi_dimensions, state.level, x, y, tile_size);
+                    (x, copy_pixels_to_tile(&state.strip.pixels, state.strip.width, state.strip.first_row, &bounds))
+                })
+                .collect();
+            self.sink.write_tile_row(state.level, y, tiles);
+            state.next_tile_row += 1;
+        }
+    }
+
+    // Halves the next band of rows, once the rows below it that the filter reaches into have arrived
+    fn scale_next_band(&mut self, index: usize) -> Option<Vec<Rgba>> {
+        let state = &mut self.levels[index];
+        let start = state.next_scaled_row;
+        if start >= state.height {
+            return None;
+        }
+
+        let end = (start + self.band_rows * 2).min(state.height);
+        let context_end = (end + SCALE_MARGIN * 2).min(state.height);
+        if state.strip.end_row() < context_end {
+            return None;
+        }
+        let context_start = start - start.min(SCALE_MARGIN * 2);
+        let count = (end - start).div_ceil(2);
+
+        // A band that only covers tiles outside the footprint is all transparent, and so is its scaled copy
+        let tile_rows = (context_start / self.tile_size)..=((context_end - 1) / self.tile_size);
+        if self.footprint.is_some_and(|footprint| !footprint.has_tiles_in_rows(state.level, tile_rows)) {
+            state.next_scaled_row = end;
+            let scaled_width = state.width.div_ceil(2);
+            return Some(vec![Rgba { r: 0, g: 0, b: 0, a: 0 }; (count * scaled_width) as usize]);
+        }
+
+        let band = state.strip.to_image(context_start, context_end);
+        let scaled = prepare_scaled_band(band, self.filters);
+
+        // Only keep the rows that belong to this band
+        let first = (start - context_start) / 2;
+        let scaled_width = scaled.width() as usize;
+        let from = first as usize * scaled_width;
+        let to = (first + count) as usize * scaled_width;
+
+        state.next_scaled_row = end;
+        return Some(scaled.data[from..to].to_vec());
+    }
+
+    // Forgets the rows that no tile row or scaled band will look at again
+    fn drop_finished_rows(&mut self, index: usize) {
+        let state = &mut self.levels[index];
+        let tile_rows = state.height.div_ceil(self.tile_size);
+
+        let needed_for_tiles = if state.next_tile_row < tile_rows {
+            (state.next_tile_row * self.tile_size).saturating_sub(self.dzi_dimensions.overlap)
+        } else {
+            state.height
+        };
+        let needed_for_scaling = if state.next_scaled_row < state.height {
+            state.next_scaled_row.saturating_sub(SCALE_MARGIN * 2)
+        } else {
+            state.height
+        };
+
+        state.strip.drop_rows_before(needed_for_tiles.min(needed_for_scaling));
+    }
+}
+
+pub fn calculate_zoom_levels(height: u32, width: u32, tile_size: u32) -> u32 {
+
+    let mut h = height;
+    let mut w = width;
+
+    let mut zoom_levels = 0;
+    while h > tile_size || w > tile_size {
+        h /= 2;
+        w /= 2;
+        zoom_levels += 1;
+    }
+    return zoom_levels;
+}
+
+// The levels below the full resolution one that the map layouts keep, so that their coarsest level is one tile
+pub fn calculate_single_tile_levels(height: u32, width: u32, tile_size: u32) -> u32 {
+    let mut levels = 0;
+    while height.max(width).div_ceil(1 << levels) > tile_size {
+        levels += 1;
+    }
+    return levels;
+}
+
+// The DZI level of the full-resolution image: ceil(log2(max dimension)), so that level 0 is 1x1 px
+pub fn calculate_max_level(height: u32, width: u32) -> u32 {
+    let max_dimension = height.max(width);
+    if max_dimension <= 1 {
+        return 0;
+    }
+    return u32::BITS - (max_dimension - 1).leading_zeros();
+}
+
+// The width and height of a level, halving (and rounding up) once per level below the top
+pub fn get_level_dimensions(dzi_dimensions: &DZIDimensions, level: u32) -> (u32, u32) {
+    let scale = 1_u64 << (dzi_dimensions.max_level - level);
+    let width = (dzi_dimensions.width as u64).div_ceil(scale) as u32;
+    let height = (dzi_dimensions.height as u64).div_ceil(scale) as u32;
+    return (width, height);
+}
+
+// The number of tile columns and rows in a level
+pub fn get_level_tile_count(dzi_dimensions: &DZIDimensions, level: u32, tile_size: u32) -> (u32, u32) {
+    let (width, height) = get_level_dimensions(dzi_dimensions, level);
+    return (width.div_ceil(tile_size), height.div_ceil(tile_size));
+}
+
+// Works out which pixels of its level a tile holds. Every edge that faces another tile
+// is pushed out by the overlap, as described by the DZI format. Tiles on the right and
+// bottom edges are cropped to whatever is left of the level.
+pub fn get_tile_bounds(dzi_dimensions: &DZIDimensions, level: u32, x: u32, y: u32, tile_size: u32) -> TileBounds {
+    let (level_width, level_height) = get_level_dimensions(dzi_dimensions, level);
+    let overlap = dzi_dimensions.overlap;
+
+    let start_x = (x * tile_size).saturating_sub(overlap);
+    let start_y = (y * tile_size).saturating_sub(overlap);
+    let end_x = ((x + 1) * tile_size + overlap).min(level_width);
+    let end_y = ((y + 1) * tile_size + overlap).min(level_height);
+
+    return TileBounds {
+        x: start_x,
+        y: start_y,
+        width: end_x - start_x,
+        height: end_y - start_y,
+    };
+}
+
+// Cuts a tile out of some whole rows of its level, `width` pixels wide and starting at `first_row`
+pub fn copy_pixels_to_tile(rows: &[Rgba], width: u32, first_row: u32, bounds: &TileBounds) -> Image<Rgba> {
+    let mut pixels = Vec::with_capacity((bounds.width * bounds.height) as usize);
+    for y in bounds.y..bounds.y + bounds.height {
+        let start = ((y - first_row) * width) as usize;
+        let row = &rows[start..start + width as usize];
+        pixels.extend_from_slice(&row[bounds.x as usize..(bounds.x + bounds.width) as usize]);
+    }
+    return Image::from_pixels(bounds.width, pixels);
+}
+
+// Grows an image to an even width and height by repeating its last column and row, so
+// that halving it lines every output pixel up with exactly two real source pixels.
+fn pad_to_even(image: Image<Rgba>) -> Image<Rgba> {
+    let width = image.width();
+    let height = image.height();
+    if width.is_multiple_of(2) && height.is_multiple_of(2) {
+        return image;
+    }
+
+    let padded_width = width.div_ceil(2) * 2;
+    let padded_height = height.div_ceil(2) * 2;
+    return Image::from_fn(padded_width, padded_height, |x, y| {
+        *image.pixel(x.min(width - 1), y.min(height - 1))
+    });
+}
+
+// Scales a band of whole rows down by half. When the channels have different filters, the band is scaled once
+// with each, and every channel is taken from its own filter's result.
+pub fn prepare_scaled_band(band: Image<Rgba>, filters: ChannelFilters) -> Image<Rgba> {
+    let padded = pad_to_even(band);
+    let all_same = [filters.g, filters.b, filters.a].iter().all(|&filter| filter == filters.r);
+    if all_same {
+        return scale_band(padded, filters.r);
+    }
+
+    let mut scaled: Vec<(ReductionFilter, Image<Rgba>)> = Vec::new();
+    for filter in [filters.r, filters.g, filters.b, filters.a] {
+        if !scaled.iter().any(|(scaled_filter, _)| *scaled_filter == filter) {
+            scaled.push((filter, scale_band(padded.clone(), filter)));
+        }
+    }
+    let get_scaled = |filter: ReductionFilter| {
+        let (_, image) = scaled
+            .iter()
+            .find(|(scaled_filter, _)| *scaled_filter == filter)
+            .expect("Every channel's filter was scaled with");
+        return image;
+    };
+    let red = get_scaled(filters.r);
+    let green = get_scaled(filters.g);
+    let blue = get_scaled(filters.b);
+    let alpha = get_scaled(filters.a);
+    return Image::from_fn(red.width(), red.height(), |x, y| Rgba {
+        r: red.pixel(x, y).r,
+        g: green.pixel(x, y).g,
+        b: blue.pixel(x, y).b,
+        a: alpha.pixel(x, y).a,
+    });
+}
+
+// Scales an even-sized band down by half with one filter
+fn scale_band(padded: Image<Rgba>, filter: ReductionFilter) -> Image<Rgba> {
+    let scaled_width = padded.width() / 2;
+    let scaled_height = padded.height() / 2;
+
+    match filter {
+        ReductionFilter::Bilinear => {
+            padded.resized(scaled_width, scaled_height, ril::ResizeAlgorithm::Bilinear)
+        }
+        ReductionFilter::Lanczos => {
+            padded.resized(scaled_width, scaled_height, ril::ResizeAlgorithm::Lanczos3)
+        }
+        ReductionFilter::Box => reduce_blocks(&padded, box_pixel),
+        ReductionFilter::Nearest => reduce_blocks(&padded, |block| block[0]),
+        ReductionFilter::Or => reduce_blocks(&padded, or_pixel),
+        ReductionFilter::Mode => reduce_blocks(&padded, mode_pixel),
+    }
+}
+
+// Halves an even-sized image by turning each 2x2 block (in reading order) into one pixel
+fn reduce_blocks(image: &Image<Rgba>, reduce: impl Fn([Rgba; 4]) -> Rgba) -> Image<Rgba> {
+    return Image::from_fn(image.width() / 2, image.height() / 2, |x, y| {
+        reduce([
+            *image.pixel(x * 2, y * 2),
+            *image.pixel(x * 2 + 1, y * 2),
+            *image.pixel(x * 2, y * 2 + 1),
+            *image.pixel(x * 2 + 1, y * 2 + 1),
+        ])
+    });
+}
+
+fn box_pixel(block: [Rgba; 4]) -> Rgba {
+    let mean = |channel: fn(&Rgba) -> u8| {
+        let sum: u32 = block.iter().map(|pixel| channel(pixel) as u32).sum();
+        ((sum + 2) / 4) as u8
+    };
+    return Rgba {
+        r: mean(|pixel| pixel.r),
+        g: mean(|pixel| pixel.g),
+        b: mean(|pixel| pixel.b),
+        a: mean(|pixel| pixel.a),
+    };
+}
+
+fn or_pixel(block: [Rgba; 4]) -> Rgba {
+    return block.iter().fold(Rgba { r: 0, g: 0, b: 0, a: 0 }, |combined, pixel| Rgba {
+        r: combined.r | pixel.r,
+        g: combined.g | pixel.g,
+        b: combined.b | pixel.b,
+        a: combined.a | pixel.a,
+    });
+}
+
+fn mode_pixel(block: [Rgba; 4]) -> Rgba {
+    let mut best = block[0];
+    let mut best_count = 0;
+    for candidate in block {
+        let count = block.iter().filter(|&&pixel| pixel == candidate).count();
+        if count > best_count {
+            best = candidate;
+            best_count = count;
+        }
+    }
+    return best;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_dimensions(width: u32, height: u32, tile_size: u32) -> DZIDimensions {
+        return DZIDimensions {
+            width,
+            height,
+            zoom_levels: calculate_zoom_levels(height, width, tile_size),
+            single_tile_levels: calculate_single_tile_levels(height, width, tile_size),
+            max_level: calculate_max_level(height, width),
+            overlap: 0,
+        };
+    }
+
+    #[test]
+    fn legacy_level_count_rounds_down() {
+        assert_eq!(calculate_zoom_levels(256, 256, 256), 0);
+        assert_eq!(calculate_zoom_levels(512, 512, 256), 1);
+        // 513 / 2 = 256 fits, even though the level it stands for is 257 px
+        assert_eq!(calculate_zoom_levels(513, 513, 256), 1);
+        assert_eq!(calculate_zoom_levels(1000, 300, 256), 2);
+    }
+
+    #[test]
+    fn single_tile_level_count_rounds_up() {
+        assert_eq!(calculate_single_tile_levels(256, 256, 256), 0);
+        assert_eq!(calculate_single_tile_levels(512, 512, 256), 1);
+        assert_eq!(calculate_single_tile_levels(513, 513, 256), 2);
+        assert_eq!(calculate_single_tile_levels(1000, 300, 256), 2);
+    }
+
+    #[test]
+    fn single_tile_levels_end_on_one_tile() {
+        for size in [1, 255, 256, 257, 511, 512, 513, 1000, 1025, 37_000] {
+            for tile_size in [254, 256, 510] {
+                let dzi_dimensions = get_dimensions(size, size / 2 + 1, tile_size);
+                let lowest_level = dzi_dimensions.max_level - dzi_dimensions.single_tile_levels;
+                assert_eq!(get_level_tile_count(&dzi_dimensions, lowest_level, tile_size), (1, 1));
+                if lowest_level < dzi_dimensions.max_level {
+                    assert_ne!(get_level_tile_count(&dzi_dimensions, lowest_level + 1, tile_size), (1, 1));
+                }
+            }
+        }
+    }
+
+    // Keeps every tile it is handed, by level, column and row
+    struct CollectedTiles(Vec<(u32, u32, u32, Image<Rgba>)>);
+
+    impl TileSink for CollectedTiles {
+        fn write_tile_row(&mut self, level: u32, y: u32, tiles: Vec<(u32, Image<Rgba>)>) {
+            self.0.extend(tiles.into_iter().map(|(x, tile)| (level, x, y, tile)));
+        }
+    }
+
+    #[test]
+    fn footprint_tiles_are_cut_and_the_rest_is_transparent() {
+        let dzi_dimensions = get_dimensions(1024, 1024, 256);
+        let footprint = TileFootprint::from_roi(&dzi_dimensions, 256, &[0, 0, 10, 10]);
+        let white = Rgba { r: 255, g: 255, b: 255, a: 255 };
+        let mut tiles = CollectedTiles(Vec::new());
+        let max_level = dzi_dimensions.max_level;
+        let mut builder = PyramidBuilder::new(
+            &dzi_dimensions,
+            256,
+            1,
+            max_level,
+            max_level - 2..=max_level,
+            ChannelFilters::all(ReductionFilter::Box),
+            &mut tiles,
+        )
+        .with_footprint(Some(&footprint));
+        builder.push_rows(&vec![white; 1024 * 1024]);
+        builder.finish();
+
+        let cut: Vec<_> = tiles.0.iter().map(|(level, x, y, _)| (*level, *x, *y)).collect();
+        assert_eq!(cut, vec![(max_level, 0, 0), (max_level - 1, 0, 0), (max_level - 2, 0, 0)]);
+        // The single tile of the coarsest level is scaled down from one white tile and three left out ones
+        let (_, _, _, coarsest) = &tiles.0[2];
+        assert_eq!(*coarsest.pixel(10, 10), white);
+        assert_eq!(coarsest.pixel(200, 200).a, 0);
+    }
+}
@@ -0,0 +1,106 @@
+use png::{BitDepth, ColorType, Encoder};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use crate::app;
+use crate::dzi_layout::{get_descriptor_path, get_level_from_name, get_level_name, get_lowest_level, read_descriptor, DZILayout, DZIOutput};
+use crate::dzi_pyramid::{calculate_max_level, calculate_single_tile_levels, calculate_zoom_levels, DZIDimensions};
+use crate::dzi_source::{RowSource, SavedLevelRows};
+use crate::dzi_sparse::read_tile_index;
+use crate::tile_format::TileFormat;
+
+/// # Reassemble a level
+/// Stitches the tiles of one level back into a single PNG, one tile row at a time. Each tile gives up only its own
+/// square, so overlap is dropped, and the image is cropped to the size of the level rather than a multiple of the
+/// tile size. Tiles the tile index says were left out are filled in from it.
+pub fn do_dzi_reassemble(cli: app::DZIReassembleArgs) {
+    println!("Reassembling {}...", cli.output_folder);
+    if let DZILayout::Zarr = cli.layout {
+        panic!("Zarr stores hold raw chunks rather than tile images. Open them with a Zarr reader instead.");
+    }
+
+    let mut output = DZIOutput {
+        layout: cli.layout,
+        folder: cli.output_folder.trim_end_matches('/').to_string(),
+        stem: cli.output_file_stem,
+        // Only the extension to look for first, the others are tried as well
+        format: TileFormat::Auto,
+        quality: 0,
+        tile_size: 0,
+        base_url: String::new(),
+        is_archived: false,
+        channel_labels: Vec::new(),
+    };
+
+    let descriptor = match get_descriptor_path(&output) {
+        Some(descriptor_path) => match fs::read_to_string(&descriptor_path) {
+            Ok(text) => read_descriptor(&output.layout, &text),
+            Err(_) => Default::default(),
+        },
+        None => Default::default(),
+    };
+    let (Some(width), Some(height)) = (cli.width.or(descriptor.width), cli.height.or(descriptor.height)) else {
+        panic!("The image size is unknown. Without a descriptor, pass --width and --height.");
+    };
+    let tile_size = cli.tile_size.or(descriptor.tile_size).unwrap_or(256);
+    let overlap = cli.overlap.or(descriptor.overlap).unwrap_or(0);
+    output.tile_size = tile_size;
+    if let Some(format) = descriptor.extension.as_deref().and_then(TileFormat::from_extension) {
+        output.format = format;
+    }
+
+    let dzi_dimensions = DZIDimensions {
+        width,
+        height,
+        zoom_levels: calculate_zoom_levels(height, width, tile_size),
+        single_tile_levels: calculate_single_tile_levels(height, width, tile_size),
+        max_level: calculate_max_level(height, width),
+        overlap,
+    };
+
+    let lowest_level = get_lowest_level(&output.layout, &dzi_dimensions);
+    let level = match cli.level {
+        Some(name) => get_level_from_name(&output.layout, &dzi_dimensions, name),
+        None => dzi_dimensions.max_level,
+    };
+    if level < lowest_level || level > dzi_dimensions.max_level {
+        panic!(
+            "Level must be between {} and {}.",
+            get_level_name(&output.layout, &dzi_dimensions, lowest_level),
+            get_level_name(&output.layout, &dzi_dimensions, dzi_dimensions.max_level)
+        );
+    }
+
+    let left_out_tiles = read_tile_index(&format!("{}/{}_index.json", output.folder, output.stem));
+    let mut rows = SavedLevelRows::new(&output, &dzi_dimensions, level, tile_size, left_out_tiles);
+    let (level_width, level_height) = (rows.width(), rows.height());
+
+    let file = File::create(&cli.output_file).expect("Error creating output image");
+    let mut encoder = Encoder::new(BufWriter::new(file), level_width, level_height);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header().expect("Error writing output image");
+    let mut stream = writer.stream_writer().expect("Error writing output image");
+
+    // Only one tile row is held in memory at a time
+    let mut pixels = Vec::new();
+    let mut bytes = Vec::new();
+    loop {
+        pixels.clear();
+        if rows.read_rows(tile_size, &mut pixels) == 0 {
+            break;
+        }
+        bytes.clear();
+        bytes.extend(pixels.iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b, pixel.a]));
+        stream.write_all(&bytes).expect("Error writing output image");
+    }
+    stream.finish().expect("Error writing output image");
+
+    println!(
+        "Wrote level {} ({}x{}) to {}.",
+        get_level_name(&output.layout, &dzi_dimensions, level),
+        level_width,
+        level_height,
+        cli.output_file
+    );
+}
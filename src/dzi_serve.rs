@@ -0,0 +1,247 @@
+use ril::{Image, Rgba};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use tiny_http::{Header, Response, Server};
+
+use crate::app;
+use crate::bitmask_dzi_mode::get_channel_filters;
+use crate::bitmask_mode::merge_channels;
+use crate::dzi_layout::{get_descriptor_files, get_level_from_name, get_relative_path, get_tile_path, DZILayout, DZIOutput};
+use crate::dzi_pyramid::{
+    calculate_max_level, calculate_single_tile_levels, calculate_zoom_levels, copy_pixels_to_tile, get_level_tile_count,
+    get_tile_bounds, prepare_scaled_band, ChannelFilters, DZIDimensions,
+};
+use crate::dzi_source::open_row_source;
+use crate::dzi_split_mode::pad_tile;
+use crate::tile_format::{encode_tile, TileFormat};
+
+/// # Serve
+/// Serves the tiles of an image over HTTP on localhost without writing its pyramid first. A level is only scaled down
+/// the first time one of its tiles is asked for, and each tile is only cut and encoded when it is asked for, then kept
+/// in the cache folder under the path it would have in a written pyramid.
+pub fn do_serve(cli: app::ServeArgs) {
+    println!("Doing serve...");
+
+    if !matches!(cli.layout, DZILayout::Dzi | DZILayout::Xyz | DZILayout::Tms) {
+        panic!("Only the dzi, xyz and tms layouts can be served.");
+    }
+    if cli.overlap >= cli.tile_size {
+        panic!("Overlap must be smaller than the tile size.");
+    }
+    if cli.overlap > 0 && !matches!(cli.layout, DZILayout::Dzi) {
+        panic!("Only the dzi layout supports overlap.");
+    }
+    if cli.quality > 100 {
+        panic!("Quality must be between 0 and 100.");
+    }
+
+    let start_time = Instant::now();
+    let (image, filters) = match (cli.input_image, cli.channels) {
+        (Some(input_image), _) => {
+            if !Path::new(&input_image).exists() {
+                panic!("Input image does not exist.");
+            }
+            let mut source = open_row_source(&input_image);
+            let mut pixels = Vec::new();
+            while source.read_rows(cli.tile_size, &mut pixels) > 0 {}
+            (Image::from_pixels(source.width(), pixels), ChannelFilters::all(cli.filter))
+        }
+        (None, Some(channels)) => {
+            let filters = get_channel_filters(&channels, cli.filter, cli.bitmask_filter);
+            let image = merge_channels(channels, false).expect("Images are always merged outside a dry run");
+            (image, filters)
+        }
+        (None, None) => panic!("Give an input image, or the bitmask-mode options to merge one."),
+    };
+    println!("Image loaded in {:?}", start_time.elapsed());
+    // The descriptor names one format, so auto decides it for the whole image
+    let format = cli.format.resolve(image.data.iter().all(|pixel| pixel.a == 255));
+
+    let tile_size = cli.tile_size;
+    let dzi_dimensions = DZIDimensions {
+        width: image.width(),
+        height: image.height(),
+        zoom_levels: calculate_zoom_levels(image.height(), image.width(), tile_size),
+        single_tile_levels: calculate_single_tile_levels(image.height(), image.width(), tile_size),
+        max_level: calculate_max_level(image.height(), image.width()),
+        overlap: cli.overlap,
+    };
+    let output = DZIOutput {
+        layout: cli.layout,
+        folder: cli.cache_folder.trim_end_matches('/').to_string(),
+        stem: cli.output_file_stem,
+        format,
+        quality: cli.quality,
+        tile_size,
+        base_url: String::new(),
+        is_archived: false,
+        channel_labels: Vec::new(),
+    };
+
+    // The cache may hold tiles of another image, so the tiles this server would write there are cleared out
+    let cache_tree = match output.layout {
+        DZILayout::Dzi => format!("{}/{}_files", output.folder, output.stem),
+        _ => format!("{}/{}", output.folder, output.stem),
+    };
+    if Path::new(&cache_tree).exists() {
+        fs::remove_dir_all(&cache_tree).expect("Error clearing tile cache");
+    }
+    let mut cache = TileCache {
+        max_bytes: cli.cache_size * 1024 * 1024,
+        total_bytes: 0,
+        entries: VecDeque::new(),
+    };
+
+    let mut levels = LazyLevels {
+        levels: (0..dzi_dimensions.max_level).map(|_| None).chain([Some(image)]).collect(),
+        filters,
+    };
+    let descriptors: Vec<(String, String)> = get_descriptor_files(&output, &dzi_dimensions, tile_size, output.format.extension())
+        .into_iter()
+        .map(|(path, descriptor)| (get_relative_path(&output, &path).to_string(), descriptor))
+        .collect();
+
+    let server = Server::http(("127.0.0.1", cli.port)).expect("Error starting server");
+    for (path, _) in &descriptors {
+        println!("Serving http://localhost:{}/{}", cli.port, path);
+    }
+
+    for request in server.incoming_requests() {
+        let url = request.url().split(['?', '#']).next().unwrap_or("").trim_start_matches('/').to_string();
+
+        let response = if let Some((path, descriptor)) = descriptors.iter().find(|(path, _)| *path == url) {
+            let content_type = if path.ends_with(".json") { "application/json" } else { "application/xml" };
+            Some((descriptor.as_bytes().to_vec(), content_type))
+        } else {
+            find_requested_tile(&output, &dzi_dimensions, &url).map(|(level, x, y, format)| {
+                let tile_path = get_tile_path(&output, &dzi_dimensions, level, x, y, format.extension());
+                let bytes = match fs::read(&tile_path) {
+                    Ok(bytes) => bytes,
+                    Err(_) => {
+                        let level_image = levels.get(&dzi_dimensions, level);
+                        let bounds = get_tile_bounds(&dzi_dimensions, level, x, y, tile_size);
+                        let tile = copy_pixels_to_tile(&level_image.data, level_image.width(), 0, &bounds);
+                        // Map viewers stretch every tile to the full tile size, as in dzi-split-mode
+                        let tile = match output.layout {
+                            DZILayout::Xyz | DZILayout::Tms => pad_tile(tile, tile_size),
+                            _ => tile,
+                        };
+                        let bytes = encode_tile(&tile, format, output.quality);
+                        cache.add(&tile_path, &bytes);
+                        bytes
+                    }
+                };
+                let content_type = match format {
+                    TileFormat::Png => "image/png",
+                    TileFormat::Webp => "image/webp",
+                    _ => "image/jpeg",
+                };
+                (bytes, content_type)
+            })
+        };
+
+        // Viewers are often opened from another origin, or straight from a file
+        let allow_origin = Header::from_bytes("Access-Control-Allow-Origin", "*").expect("Valid header");
+        let result = match response {
+            Some((bytes, content_type)) => {
+                let content_type = Header::from_bytes("Content-Type", content_type).expect("Valid header");
+                request.respond(Response::from_data(bytes).with_header(content_type).with_header(allow_origin))
+            }
+            None => request.respond(Response::from_string("Not found").with_status_code(404).with_header(allow_origin)),
+        };
+        if let Err(error) = result {
+            println!("Error answering request for /{}: {}", url, error);
+        }
+    }
+}
+
+// Works out which tile a URL path is asking for, and in which format. The level and tile numbers are the last three
+// numbers in the path, and it only counts as a tile if it is exactly the path that tile has in the layout.
+fn find_requested_tile(output: &DZIOutput, dzi_dimensions: &DZIDimensions, url: &str) -> Option<(u32, u32, u32, TileFormat)> {
+    let (path, extension) = url.rsplit_once('.')?;
+    let format = TileFormat::from_extension(extension)?;
+    let numbers: Vec<u32> = path
+        .strip_prefix(&output.stem)?
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|number| number.parse().ok())
+        .collect();
+    let [level_name, x, y] = numbers[numbers.len().checked_sub(3)?..] else {
+        return None;
+    };
+
+    if level_name > dzi_dimensions.max_level {
+        return None;
+    }
+    let level = get_level_from_name(&output.layout, dzi_dimensions, level_name);
+    if level > dzi_dimensions.max_level {
+        return None;
+    }
+    let (_, rows) = get_level_tile_count(dzi_dimensions, level, output.tile_size);
+    // TMS counts rows up from the bottom of a 2^z by 2^z grid
+    let y = match output.layout {
+        DZILayout::Tms => ((1_u32 << level_name) - 1).checked_sub(y)?,
+        _ => y,
+    };
+    let (cols, _) = get_level_tile_count(dzi_dimensions, level, output.tile_size);
+    if x >= cols || y >= rows {
+        return None;
+    }
+
+    let tile_path = get_tile_path(output, dzi_dimensions, level, x, y, extension);
+    if get_relative_path(output, &tile_path) != url {
+        return None;
+    }
+    return Some((level, x, y, format));
+}
+
+// The levels of the pyramid, each scaled down from the one above the first time it is needed and then kept
+struct LazyLevels {
+    levels: Vec<Option<Image<Rgba>>>,
+    filters: ChannelFilters,
+}
+
+impl LazyLevels {
+    fn get(&mut self, dzi_dimensions: &DZIDimensions, level: u32) -> &Image<Rgba> {
+        let scaled_from = (level..=dzi_dimensions.max_level)
+            .find(|&level| self.levels[level as usize].is_some())
+            .expect("The full resolution level is always there");
+        for scaled_level in (level..scaled_from).rev() {
+            let start_time = Instant::now();
+            let above = self.levels[scaled_level as usize + 1].as_ref().expect("Levels are scaled from the top down");
+            let scaled = prepare_scaled_band(above.clone(), self.filters);
+            println!("Scaled down level {} in {:?}", scaled_level, start_time.elapsed());
+            self.levels[scaled_level as usize] = Some(scaled);
+        }
+        return self.levels[level as usize].as_ref().expect("The level was just scaled");
+    }
+}
+
+// Tiles saved in the cache folder, oldest first, so the oldest can be deleted once they take up too much room
+struct TileCache {
+    max_bytes: u64,
+    total_bytes: u64,
+    entries: VecDeque<(String, u64)>,
+}
+
+impl TileCache {
+    fn add(&mut self, path: &str, bytes: &[u8]) {
+        if let Some(folder) = Path::new(path).parent() {
+            fs::create_dir_all(folder).expect("Error creating cache folder");
+        }
+        fs::write(path, bytes).expect("Error saving tile to the cache");
+        self.entries.push_back((path.to_string(), bytes.len() as u64));
+        self.total_bytes += bytes.len() as u64;
+
+        while self.total_bytes > self.max_bytes {
+            let Some((oldest_path, size)) = self.entries.pop_front() else {
+                break;
+            };
+            if fs::remove_file(&oldest_path).is_err() {
+                println!("Could not delete cached tile {}", oldest_path);
+            }
+            self.total_bytes -= size;
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use png::{BitDepth, ColorType, Transformations};
+use ril::{Image, Rgba};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use tiff::decoder::{ChunkType, Decoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+use tiff::ColorType as TiffColorType;
+
+use crate::dzi_layout::{find_tile_path, DZIOutput};
+use crate::dzi_pyramid::{get_level_dimensions, get_level_tile_count, get_tile_bounds, DZIDimensions};
+use crate::dzi_sparse::{find_index_entry, IndexEntry};
+
+// The TIFF Compression tag value for JPEG
+const COMPRESSION_JPEG: u16 = 7;
+
+/// # Row source
+/// Something the pyramid can be fed from, top to bottom, a few whole rows at a time.
+pub trait RowSource {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// Reads up to `rows` more rows, appends their pixels, and returns how many rows were read
+    fn read_rows(&mut self, rows: u32, pixels: &mut Vec<Rgba>) -> u32;
+
+    /// A source for a smaller copy of the same image at exactly this size, if the file already has one
+    fn open_reduced_level(&self, _width: u32, _height: u32) -> Option<Box<dyn RowSource>> {
+        return None;
+    }
+}
+
+// An image that has already been decoded into memory
+pub struct ImageRows {
+    image: Image<Rgba>,
+    next_row: u32,
+}
+
+impl ImageRows {
+    pub fn new(image: Image<Rgba>) -> Self {
+        return ImageRows { image, next_row: 0 };
+    }
+}
+
+impl RowSource for ImageRows {
+    fn width(&self) -> u32 {
+        return self.image.width();
+    }
+
+    fn height(&self) -> u32 {
+        return self.image.height();
+    }
+
+    fn read_rows(&mut self, rows: u32, pixels: &mut Vec<Rgba>) -> u32 {
+        let count = rows.min(self.image.height() - self.next_row);
+        let width = self.image.width() as usize;
+        let from = self.next_row as usize * width;
+        let to = from + count as usize * width;
+        pixels.extend_from_slice(&self.image.data[from..to]);
+        self.next_row += count;
+        return count;
+    }
+}
+
+// A PNG that is decoded one row at a time, so that only the rows being read are ever in memory
+pub struct PngRows {
+    reader: png::Reader<BufReader<File>>,
+    color_type: ColorType,
+    width: u32,
+    height: u32,
+}
+
+impl PngRows {
+    /// Opens a PNG for streaming. Interlaced PNGs can't be read row by row, so they give None.
+    pub fn open(path: &str) -> Option<Self> {
+        let file = File::open(path).expect("Error opening image");
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        // Palettes, transparency chunks and 16-bit samples all come out as plain 8-bit colour
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let reader = decoder.read_info().expect("Error reading PNG header");
+
+        let info = reader.info();
+        if info.interlaced {
+            return None;
+        }
+        let (width, height) = (info.width, info.height);
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != BitDepth::Eight {
+            panic!("Unsupported PNG bit depth: {:?}", bit_depth);
+        }
+
+        return Some(PngRows {
+            reader,
+            color_type,
+            width,
+            height,
+        });
+    }
+}
+
+impl RowSource for PngRows {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn read_rows(&mut self, rows: u32, pixels: &mut Vec<Rgba>) -> u32 {
+        let mut count = 0;
+        while count < rows {
+            let row = match self.reader.next_row().expect("Error decoding PNG row") {
+                Some(row) => row,
+                None => break,
+            };
+            let data = row.data();
+            match self.color_type {
+                ColorType::Grayscale => {
+                    pixels.extend(data.iter().map(|&l| Rgba { r: l, g: l, b: l, a: 255 }));
+                }
+                ColorType::GrayscaleAlpha => {
+                    pixels.extend(data.chunks_exact(2).map(|p| Rgba { r: p[0], g: p[0], b: p[0], a: p[1] }));
+                }
+                ColorType::Rgb => {
+                    pixels.extend(data.chunks_exact(3).map(|p| Rgba { r: p[0], g: p[1], b: p[2], a: 255 }));
+                }
+                ColorType::Rgba => {
+                    pixels.extend(data.chunks_exact(4).map(|p| Rgba { r: p[0], g: p[1], b: p[2], a: p[3] }));
+                }
+                ColorType::Indexed => {
+                    panic!("Indexed PNG rows should have been expanded by the decoder");
+                }
+            }
+            count += 1;
+        }
+        return count;
+    }
+}
+
+// A TIFF image that is decoded one row of tiles (or one strip) at a time. Plain and BigTIFF files both work, as do
+// the JPEG-compressed tiles of most slide scanners.
+pub struct TiffRows {
+    path: String,
+    decoder: Decoder<BufReader<File>>,
+    width: u32,
+    height: u32,
+    // Samples in each pixel: grey, RGB or RGBA
+    channels: usize,
+    chunk_height: u32,
+    chunks_across: u32,
+    next_chunk_row: u32,
+    next_row: u32,
+    // Rows decoded from the last chunk row that have not been handed out yet
+    pending: Vec<Rgba>,
+}
+
+impl TiffRows {
+    /// Opens one image of a TIFF, counting from 0 in the order they are chained in the file
+    pub fn open(path: &str, image_index: usize) -> Self {
+        let file = File::open(path).expect("Error opening image");
+        let mut decoder = Decoder::new(BufReader::new(file))
+            .expect("Error reading TIFF header")
+            .with_limits(Limits::unlimited());
+        decoder.seek_to_image(image_index).expect("Error reading TIFF image");
+
+        let (width, height) = decoder.dimensions().expect("Error reading TIFF image size");
+        let compression = decoder.find_tag_unsigned::<u16>(Tag::Compression).ok().flatten();
+        let channels = match decoder.colortype().expect("Unsupported TIFF colour type") {
+            TiffColorType::Gray(8 | 16) => 1,
+            TiffColorType::RGB(8 | 16) => 3,
+            TiffColorType::RGBA(8 | 16) => 4,
+            // The JPEG decoder hands YCbCr tiles back as RGB
+            TiffColorType::YCbCr(8) if compression == Some(COMPRESSION_JPEG) => 3,
+            color_type => panic!("Unsupported TIFF colour type: {:?}", color_type),
+        };
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let chunks_across = match decoder.get_chunk_type() {
+            ChunkType::Tile => width.div_ceil(chunk_width),
+            ChunkType::Strip => 1,
+        };
+
+        return TiffRows {
+            path: path.to_string(),
+            decoder,
+            width,
+            height,
+            channels,
+            chunk_height,
+            chunks_across,
+            next_chunk_row: 0,
+            next_row: 0,
+            pending: Vec::new(),
+        };
+    }
+
+    fn read_chunk_row(&mut self) {
+        let top = self.next_chunk_row * self.chunk_height;
+        let row_height = self.chunk_height.min(self.height - top);
+        let mut pixels = vec![Rgba { r: 0, g: 0, b: 0, a: 255 }; (self.width * row_height) as usize];
+
+        let mut left = 0;
+        for x in 0..self.chunks_across {
+            let chunk_index = self.next_chunk_row * self.chunks_across + x;
+            let (chunk_width, chunk_height) = self.decoder.chunk_data_dimensions(chunk_index);
+            if chunk_height < row_height {
+                panic!("TIFF tile {} is shorter than its row", chunk_index);
+            }
+            let samples = match self.decoder.read_chunk(chunk_index).expect("Error decoding TIFF tile") {
+                DecodingResult::U8(samples) => samples,
+                DecodingResult::U16(samples) => samples.iter().map(|sample| (sample >> 8) as u8).collect(),
+                _ => panic!("Only 8 and 16 bit TIFF samples are supported"),
+            };
+            let chunk_pixels = samples.chunks_exact(self.channels).map(|p| match p {
+                [l] => Rgba { r: *l, g: *l, b: *l, a: 255 },
+                [r, g, b] => Rgba { r: *r, g: *g, b: *b, a: 255 },
+                [r, g, b, a] => Rgba { r: *r, g: *g, b: *b, a: *a },
+                _ => unreachable!("Pixels have 1, 3 or 4 samples"),
+            });
+            for (i, pixel) in chunk_pixels.enumerate() {
+                let (i, j) = (i as u32 % chunk_width, i as u32 / chunk_width);
+                if j < row_height && left + i < self.width {
+                    pixels[(j * self.width + left + i) as usize] = pixel;
+                }
+            }
+            left += chunk_width;
+        }
+
+        self.pending.extend(pixels);
+        self.next_chunk_row += 1;
+    }
+}
+
+impl RowSource for TiffRows {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn read_rows(&mut self, rows: u32, pixels: &mut Vec<Rgba>) -> u32 {
+        let count = rows.min(self.height - self.next_row);
+        let wanted = count as usize * self.width as usize;
+        while self.pending.len() < wanted {
+            self.read_chunk_row();
+        }
+        pixels.extend(self.pending.drain(..wanted));
+        self.next_row += count;
+        return count;
+    }
+
+    // Slide scanners and COGs chain their reduced resolution levels after the full resolution image
+    fn open_reduced_level(&self, width: u32, height: u32) -> Option<Box<dyn RowSource>> {
+        let file = File::open(&self.path).expect("Error opening image");
+        let mut decoder = Decoder::new(BufReader::new(file)).expect("Error reading TIFF header");
+        let mut image_index = 0;
+        loop {
+            if decoder.dimensions().ok() == Some((width, height)) && image_index > 0 {
+                return Some(Box::new(TiffRows::open(&self.path, image_index)));
+            }
+            if !decoder.more_images() || decoder.next_image().is_err() {
+                return None;
+            }
+            image_index += 1;
+        }
+    }
+}
+
+/// # Open row source
+/// Given a path to an image, return a source that reads its rows.
+///
+/// PNGs and TIFFs are streamed, so peak memory depends on the image width rather than its area.
+/// Anything else (or an interlaced PNG) is decoded into memory in one go by ril.
+pub fn open_row_source(path: &str) -> Box<dyn RowSource> {
+    let lowercase_path = path.to_lowercase();
+    if lowercase_path.ends_with(".tif") || lowercase_path.ends_with(".tiff") {
+        println!("Streaming TIFF tiles...");
+        return Box::new(TiffRows::open(path, 0));
+    }
+
+    let is_png = lowercase_path.ends_with(".png");
+    if is_png {
+        if let Some(source) = PngRows::open(path) {
+            println!("Streaming PNG rows...");
+            return Box::new(source);
+        }
+        println!("Interlaced PNG, loading the whole image...");
+    }
+
+    let loaded_image: Image<Rgba> = Image::open(path).expect("Error loading image: ");
+    return Box::new(ImageRows::new(loaded_image));
+}
+
+/// # Is opaque
+/// Given a source, read it to the end and return whether every pixel is fully opaque. It stops at the first pixel
+/// that isn't.
+pub fn is_opaque(source: &mut dyn RowSource) -> bool {
+    let mut pixels = Vec::new();
+    while source.read_rows(64, &mut pixels) > 0 {
+        if pixels.iter().any(|pixel| pixel.a != 255) {
+            return false;
+        }
+        pixels.clear();
+    }
+    return true;
+}
+
+// Reads a level that is already on disk back as rows, one tile row at a time. Each tile
+// only gives up its own tile_size square, the overlap is left to its neighbours. Tiles
+// that a tile index says were left out are filled in from it.
+pub struct SavedLevelRows<'a> {
+    output: &'a DZIOutput,
+    dzi_dimensions: &'a DZIDimensions,
+    level: u32,
+    tile_size: u32,
+    width: u32,
+    height: u32,
+    next_tile_row: u32,
+    // Rows read from the last tile row that have not been handed out yet
+    pending: Vec<Rgba>,
+    left_out_tiles: HashMap<String, IndexEntry>,
+}
+
+impl<'a> SavedLevelRows<'a> {
+    pub fn new(
+        output: &'a DZIOutput,
+        dzi_dimensions: &'a DZIDimensions,
+        level: u32,
+        tile_size: u32,
+        left_out_tiles: HashMap<String, IndexEntry>,
+    ) -> Self {
+        let (width, height) = get_level_dimensions(dzi_dimensions, level);
+        return SavedLevelRows {
+            output,
+            dzi_dimensions,
+            level,
+            tile_size,
+            width,
+            height,
+            next_tile_row: 0,
+            pending: Vec::new(),
+            left_out_tiles,
+        };
+    }
+
+    fn read_tile_row(&mut self) {
+        let y = self.next_tile_row;
+        let top = y * self.tile_size;
+        let row_height = self.tile_size.min(self.height - top);
+        let mut pixels = vec![Rgba { r: 0, g: 0, b: 0, a: 0 }; (self.width * row_height) as usize];
+
+        let (cols, _) = get_level_tile_count(self.dzi_dimensions, self.level, self.tile_size);
+        for x in 0..cols {
+            let bounds = get_tile_bounds(self.dzi_dimensions, self.level, x, y, self.tile_size);
+            let tile_path = find_tile_path(self.output, self.dzi_dimensions, self.level, x, y);
+            let tile: Image<Rgba> = match (tile_path, self.find_left_out_tile(x, y)) {
+                (Some(tile_path), _) => Image::open(&tile_path).expect("Error loading saved tile"),
+                (None, Some(&IndexEntry::Blank([r, g, b, a]))) => Image::new(bounds.width, bounds.height, Rgba { r, g, b, a }),
+                (None, Some(IndexEntry::SameAs(original))) => {
+                    Image::open(format!("{}/{}", self.output.folder, original)).expect("Error loading saved tile")
+                }
+                (None, Some(IndexEntry::OutsideFootprint)) => {
+                    Image::new(bounds.width, bounds.height, Rgba { r: 0, g: 0, b: 0, a: 0 })
+                }
+                (None, None) => panic!("Tile missing from saved layer"),
+            };
+            let left = x * self.tile_size;
+            let core_width = self.tile_size.min(self.width - left);
+
+            for j in 0..row_height {
+                for i in 0..core_width {
+                    let pixel = tile.pixel(left + i - bounds.x, top + j - bounds.y);
+                    pixels[(j * self.width + left + i) as usize] = *pixel;
+                }
+            }
+        }
+
+        self.pending.extend(pixels);
+        self.next_tile_row += 1;
+    }
+
+    fn find_left_out_tile(&self, x: u32, y: u32) -> Option<&IndexEntry> {
+        let entry = find_index_entry(&self.left_out_tiles, self.output, self.dzi_dimensions, self.level, x, y);
+        return entry.map(|(_, entry)| entry);
+    }
+}
+
+impl RowSource for SavedLevelRows<'_> {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn read_rows(&mut self, rows: u32, pixels: &mut Vec<Rgba>) -> u32 {
+        let (_, tile_rows) = get_level_tile_count(self.dzi_dimensions, self.level, self.tile_size);
+        while self.pending.len() < (rows * self.width) as usize && self.next_tile_row < tile_rows {
+            self.read_tile_row();
+        }
+
+        let count = rows.min((self.pending.len() / self.width as usize) as u32);
+        pixels.extend(self.pending.drain(..(count * self.width) as usize));
+        return count;
+    }
+}
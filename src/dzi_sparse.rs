@@ -0,0 +1,169 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use ril::{Image, Rgba};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::Hasher;
+
+use crate::dzi_layout::{get_relative_path, get_tile_path, DZIOutput};
+use crate::dzi_pyramid::DZIDimensions;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DedupMode {
+    /// Duplicate tiles are hard links to the first tile with the same content
+    Hardlink,
+    /// Duplicate tiles are left out, and the tile index points them at the first tile with the same content
+    Index,
+}
+
+impl ValueEnum for DedupMode {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[DedupMode::Hardlink, DedupMode::Index]
+    }
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            DedupMode::Hardlink => Some(PossibleValue::new("hardlink")),
+            DedupMode::Index => Some(PossibleValue::new("index")),
+        }
+    }
+}
+
+/// # Blank color
+/// Given a tile, return the one color it is filled with if it has nothing in it, that is if it is fully transparent
+/// or, like the background of bitmask-mode images, entirely opaque black.
+pub fn get_blank_color(tile: &Image<Rgba>) -> Option<Rgba> {
+    if tile.data.iter().all(|pixel| pixel.a == 0) {
+        return Some(Rgba { r: 0, g: 0, b: 0, a: 0 });
+    }
+    let black = Rgba { r: 0, g: 0, b: 0, a: 255 };
+    if tile.data.iter().all(|pixel| *pixel == black) {
+        return Some(black);
+    }
+    return None;
+}
+
+// Why a tile has no file of its own. In JSON, a blank tile is `{ "blank": [r, g, b, a] }`, a duplicate
+// `{ "same_as": "path" }`, and a tile outside --roi or --mask `"outside_footprint"`, which is drawn transparent.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexEntry {
+    Blank([u8; 4]),
+    SameAs(String),
+    OutsideFootprint,
+}
+
+/// # Tile index
+/// The tiles of a pyramid that have no file of their own: blank tiles left out by --skip-empty, duplicates
+/// left out by --dedup index, and tiles outside --roi or --mask. Tiles are named by their path relative to the output folder.
+#[derive(Serialize)]
+pub struct TileIndex {
+    tiles: BTreeMap<String, IndexEntry>,
+    // The first tile seen with each content, by its size and two independent hashes of its bytes
+    #[serde(skip)]
+    unique_tiles: HashMap<(usize, u64, u32), String>,
+}
+
+// The tile index as read back, without the dedup bookkeeping
+#[derive(Deserialize)]
+struct TileIndexFile {
+    tiles: HashMap<String, IndexEntry>,
+}
+
+impl TileIndex {
+    pub fn new() -> Self {
+        return TileIndex {
+            tiles: BTreeMap::new(),
+            unique_tiles: HashMap::new(),
+        };
+    }
+
+    pub fn add_blank(&mut self, name: String, color: Rgba) {
+        self.tiles.insert(name, IndexEntry::Blank([color.r, color.g, color.b, color.a]));
+    }
+
+    pub fn add_duplicate(&mut self, name: String, original: String) {
+        self.tiles.insert(name, IndexEntry::SameAs(original));
+    }
+
+    pub fn add_outside_footprint(&mut self, name: String) {
+        self.tiles.insert(name, IndexEntry::OutsideFootprint);
+    }
+
+    /// Carries over an entry from the index of an earlier run, for a tile this run doesn't write
+    pub fn add_saved(&mut self, name: String, entry: IndexEntry) {
+        self.tiles.insert(name, entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.tiles.is_empty();
+    }
+
+    /// The name of an earlier tile with the same bytes, if there is one. Otherwise this tile is remembered as the
+    /// first with its content.
+    pub fn find_duplicate(&mut self, name: &str, bytes: &[u8]) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(bytes);
+        let key = (bytes.len(), hasher.finish(), crc32fast::hash(bytes));
+        if let Some(original) = self.unique_tiles.get(&key) {
+            return Some(original.clone());
+        }
+        self.unique_tiles.insert(key, name.to_string());
+        return None;
+    }
+
+    /// The index as JSON: every tile without a file, mapped to either the color to fill it with or the tile to show
+    /// in its place
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("Error writing tile index") + "\n";
+    }
+}
+
+/// # Read tile index
+/// Given the path to a tile index written by dzi-split-mode, return what it says about each tile it lists.
+/// A pyramid without an index has no tiles left out.
+pub fn read_tile_index(path: &str) -> HashMap<String, IndexEntry> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    let index: TileIndexFile = serde_json::from_str(&text).expect("Error parsing tile index");
+    return index.tiles;
+}
+
+/// # Find index entry
+/// Given the entries of a tile index, return the name and entry of a tile, whichever image format it was left out as
+pub fn find_index_entry<'a>(
+    left_out_tiles: &'a HashMap<String, IndexEntry>,
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+    level: u32,
+    x: u32,
+    y: u32,
+) -> Option<(&'a String, &'a IndexEntry)> {
+    return ["png", "jpg", "webp"].iter().find_map(|extension| {
+        let tile_path = get_tile_path(output, dzi_dimensions, level, x, y, extension);
+        left_out_tiles.get_key_value(get_relative_path(output, &tile_path))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_index_round_trips_names_that_need_escaping() {
+        let mut index = TileIndex::new();
+        index.add_blank("a \"quoted\"\\name.png".to_string(), Rgba { r: 0, g: 0, b: 0, a: 255 });
+        index.add_duplicate("b.png".to_string(), "c\nd.png".to_string());
+        index.add_outside_footprint("e.png".to_string());
+        let path = std::env::temp_dir().join(format!("tile_index_test_{}.json", std::process::id()));
+        let path = path.to_str().expect("Temporary paths are UTF-8");
+        fs::write(path, index.to_json()).expect("Error writing test index");
+
+        let tiles = read_tile_index(path);
+        fs::remove_file(path).expect("Error removing test index");
+        assert!(matches!(tiles.get("a \"quoted\"\\name.png"), Some(IndexEntry::Blank([0, 0, 0, 255]))));
+        assert!(matches!(tiles.get("b.png"), Some(IndexEntry::SameAs(original)) if original == "c\nd.png"));
+        assert!(matches!(tiles.get("e.png"), Some(IndexEntry::OutsideFootprint)));
+    }
+}
@@ -1,17 +1,29 @@
+use clap::{builder::PossibleValue, ValueEnum};
 use ril::{Image, Rgba};
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 use std::time::Instant;
 
 use crate::app;
-
-
-struct DZIDimensions {
-    cols: u32,
-    rows: u32,
-    zoom_levels: u32,
-}
-
-pub fn do_dzi_split_mode(cli: app::DZISplitModeArgs) {
+use crate::dzi_archive::{create_archive, encode_tiff_tile, ArchiveFormat, TileArchive};
+use crate::dzi_footprint::TileFootprint;
+use crate::dzi_layout::{
+    find_tile_path, get_archive_entry_name, get_descriptor_files, get_level_from_name, get_level_name, get_lowest_level,
+    get_relative_path, get_tile_folder, get_tile_path, write_descriptor, DZILayout, DZIOutput,
+};
+use crate::dzi_progress::ProgressManifest;
+use crate::dzi_pyramid::{
+    calculate_max_level, calculate_single_tile_levels, calculate_zoom_levels, get_level_dimensions, get_level_tile_count,
+    ChannelFilters, DZIDimensions, PyramidBuilder, ReductionFilter, TileSink,
+};
+use crate::dzi_source::{is_opaque, open_row_source, RowSource, SavedLevelRows};
+use crate::dzi_sparse::{find_index_entry, get_blank_color, read_tile_index, DedupMode, IndexEntry, TileIndex};
+use crate::dzi_viewer::{write_viewer, ViewerChannel};
+use crate::dzi_zarr::encode_zarr_chunk;
+use crate::tile_format::{encode_tile, TileFormat};
+
+pub fn do_dzi_split_mode(mut cli: app::DZISplitModeArgs) {
     println!("Doing DZI split mode...");
 
     let input_image_exists = Path::new(&cli.input_image).exists();
@@ -20,192 +32,536 @@ pub fn do_dzi_split_mode(cli: app::DZISplitModeArgs) {
         panic!("Input image does not exist.");
     }
 
-    println!("Loading image...");
+    println!("Opening image...");
     // get a time stamp of when this starts:
     let start_time = Instant::now();
-    let loaded_image: Image<Rgba> = Image::open(cli.input_image).expect("Error loading image: ");
+    let source = open_row_source(&cli.input_image);
 
     let end_time = Instant::now();
-    println!("Image loaded in {:?}", end_time.duration_since(start_time));
+    println!("Image opened in {:?}", end_time.duration_since(start_time));
+
+    // Every tile has to be in the format the descriptor names, so auto looks through the whole image first
+    if cli.format == TileFormat::Auto {
+        println!("Looking for transparency...");
+        cli.format = cli.format.resolve(is_opaque(open_row_source(&cli.input_image).as_mut()));
+    }
+
+    let filters = ChannelFilters::all(cli.filter);
+    tile_image(source, cli, filters, Vec::new());
+}
 
-    let height = loaded_image.height();
-    let width = loaded_image.width();
+/// # Tile image
+/// Given the rows of an image, write its pyramid as the split mode options ask, scaling each channel down with its
+/// own filter. The format has to be resolved for the image already. The input image path is only read to tell runs
+/// apart when resuming. The viewer channels are only used
+/// by --emit-viewer, to give bitmask classes their toggles.
+pub fn tile_image(
+    source: Box<dyn RowSource>,
+    cli: app::DZISplitModeArgs,
+    filters: ChannelFilters,
+    viewer_channels: Vec<ViewerChannel>,
+) {
+    let height = source.height();
+    let width = source.width();
 
     let tile_size = cli.tile_size;
-    let output_folder = cli.output_folder;
-    let output_file_stem = cli.output_file_stem;
+    if cli.overlap >= tile_size {
+        panic!("Overlap must be smaller than the tile size.");
+    }
+    if cli.overlap > 0 && !matches!(cli.layout, DZILayout::Dzi | DZILayout::Legacy) {
+        panic!("Only the dzi and legacy layouts support overlap.");
+    }
+    if cli.dedup == Some(DedupMode::Hardlink) && cli.archive.is_some() {
+        panic!("Hard links need the tiles to be written to a folder, not an archive.");
+    }
+    let is_tiff = cli.archive == Some(ArchiveFormat::Tiff);
+    if is_tiff && (cli.overlap > 0 || cli.skip_empty || cli.dedup.is_some()) {
+        panic!("A TIFF holds every tile once and without overlap, so it can't be combined with --overlap, --skip-empty or --dedup.");
+    }
+    let is_zarr = matches!(cli.layout, DZILayout::Zarr);
+    if is_zarr && (cli.archive.is_some() || cli.skip_empty || cli.dedup.is_some()) {
+        panic!("A Zarr store is a folder of its own, and missing chunks read as transparent black, so it can't be combined with --archive, --skip-empty or --dedup.");
+    }
+    if cli.emit_viewer && !matches!(cli.layout, DZILayout::Dzi | DZILayout::Legacy | DZILayout::Xyz | DZILayout::Tms) {
+        panic!("The viewer can only show the dzi, legacy, xyz and tms layouts.");
+    }
+    if cli.channel_labels.is_empty() || cli.channel_labels.len() > 4 {
+        panic!("Give between 1 and 4 channel labels.");
+    }
+    if cli.quality > 100 {
+        panic!("Quality must be between 0 and 100.");
+    }
+    if cli.format == TileFormat::Auto {
+        panic!("Auto has to be resolved to one format for the whole image before tiling.");
+    }
     let layer_to_prepare = cli.layer_to_prepare;
-    
-    
-    // let width_left = width % tile_size;
-    // let height_left = height % tile_size;
-    let cols = {
-        if width % tile_size == 0 {
-            width / tile_size
-        } else {
-            width / tile_size + 1
-        }
-    };
-    let rows = {
-        if height % tile_size == 0 {
-            height / tile_size
-        } else {
-            height / tile_size + 1
-        }
+    let output = DZIOutput {
+        layout: cli.layout,
+        folder: cli.output_folder,
+        stem: cli.output_file_stem,
+        format: cli.format,
+        quality: cli.quality,
+        tile_size,
+        base_url: cli.base_url.trim_end_matches('/').to_string(),
+        is_archived: cli.archive.is_some(),
+        channel_labels: cli.channel_labels,
     };
 
-
     let dzi_dimensions = DZIDimensions {
-        cols,
-        rows,
+        width,
+        height,
         zoom_levels: calculate_zoom_levels(height, width, tile_size),
+        single_tile_levels: calculate_single_tile_levels(height, width, tile_size),
+        max_level: calculate_max_level(height, width),
+        overlap: cli.overlap,
     };
 
-    if layer_to_prepare == 0 || layer_to_prepare == dzi_dimensions.zoom_levels {
-        println!("Preparing layer {}...", dzi_dimensions.zoom_levels);
-        let start_time = Instant::now();
-        prepare_first_layer(&loaded_image, tile_size, &dzi_dimensions, &output_folder, &output_file_stem);
-        let end_time = Instant::now();
-        println!("Layer {} prepared in {:?}", dzi_dimensions.zoom_levels, end_time.duration_since(start_time));
-    }
+    let lowest_level = get_lowest_level(&output.layout, &dzi_dimensions);
 
+    // If 0, it's all layers. Else, do the target layer only
+    let (min_level_name, max_level_name) = if layer_to_prepare != 0 {
+        (Some(layer_to_prepare), Some(layer_to_prepare))
+    } else {
+        (cli.min_level, cli.max_level)
+    };
+    let min_level = match min_level_name {
+        Some(name) => get_level_from_name(&output.layout, &dzi_dimensions, name),
+        None => lowest_level,
+    };
+    let max_level = match max_level_name {
+        Some(name) => get_level_from_name(&output.layout, &dzi_dimensions, name),
+        None => dzi_dimensions.max_level,
+    };
+    if min_level < lowest_level || max_level > dzi_dimensions.max_level || min_level > max_level {
+        panic!(
+            "Levels must be between {} and {}, with the minimum no bigger than the maximum.",
+            get_level_name(&output.layout, &dzi_dimensions, lowest_level),
+            get_level_name(&output.layout, &dzi_dimensions, dzi_dimensions.max_level)
+        );
+    }
 
-    /* 
+    // With --roi or --mask, only the tiles over the region are written, but the descriptor still covers the whole image
+    let roi_footprint = cli.roi.map(|roi| TileFootprint::from_roi(&dzi_dimensions, tile_size, &roi));
+    let mask_footprint = cli.mask.map(|mask| TileFootprint::from_mask(&dzi_dimensions, tile_size, &mask));
+    let footprint = match (roi_footprint, mask_footprint) {
+        (Some(roi_footprint), Some(mask_footprint)) => Some(roi_footprint.intersect(&mask_footprint)),
+        (roi_footprint, mask_footprint) => roi_footprint.or(mask_footprint),
+    };
+    if let Some(footprint) = &footprint {
+        let (kept, total) = footprint.count_full_resolution();
+        println!("Writing {} of {} full resolution tiles.", kept, total);
+    }
 
-    RIL has a get_pixel method.
-    This means that after loading the image, I can work out what pixels will be in each tile, and map that.
-    It means I can go tile-by-tile. It should also mean I can handle the weird left-over pixels well.
+    fs::create_dir_all(&output.folder).expect("Error creating output folder");
+
+    // With --resume, levels the manifest says are finished are left alone, and so are finished tiles in the rest
+    let progress_path = format!("{}/{}.progress", output.folder, output.stem);
+    let progress = if cli.resume {
+        let settings = get_progress_settings(&cli.input_image, &output, &dzi_dimensions, tile_size, cli.filter);
+        Some(ProgressManifest::open(&progress_path, &settings, |level, x, y, extension| {
+            get_tile_path(&output, &dzi_dimensions, level, x, y, extension)
+        }))
+    } else {
+        if Path::new(&progress_path).exists() {
+            fs::remove_file(&progress_path).expect("Error removing old progress manifest");
+        }
+        None
+    };
+    let max_level = match &progress {
+        Some(progress) => {
+            let unfinished_level = (min_level..=max_level).rev().find(|&level| {
+                let (cols, rows) = get_level_tile_count(&dzi_dimensions, level, tile_size);
+                !progress.is_level_finished(level, cols, rows)
+            });
+            match unfinished_level {
+                Some(level) => level,
+                None => {
+                    println!("All layers were already prepared.");
+                    return;
+                }
+            }
+        }
+        None => max_level,
+    };
 
-    After the initial split, I should discard the heavy initial image.
-    Then I will take four tiles, merge them to one image, scale it down by half, and save it.
+    // Tiles that were left out are listed next to the descriptor. Levels this run doesn't write keep their entries
+    // from the last run, as they may still be read back below.
+    let index_path = format!("{}/{}_index.json", output.folder, output.stem);
+    let saved_tile_index = read_tile_index(&index_path);
+    let mut tile_index = TileIndex::new();
+    for level in (lowest_level..=dzi_dimensions.max_level).filter(|level| !(min_level..=max_level).contains(level)) {
+        let (cols, rows) = get_level_tile_count(&dzi_dimensions, level, tile_size);
+        for y in 0..rows {
+            for x in 0..cols {
+                if let Some((name, entry)) = find_index_entry(&saved_tile_index, &output, &dzi_dimensions, level, x, y) {
+                    tile_index.add_saved(name.clone(), entry.clone());
+                }
+            }
+        }
+    }
 
-    I will need to repeat that until I get to a single tile.
+    // Levels below the top can be built from the next finer level that is already complete on disk
+    let saved_source_level = ((max_level + 1)..=dzi_dimensions.max_level)
+        .find(|&level| is_level_complete(&output, &dzi_dimensions, level, tile_size, &saved_tile_index));
+    let top_level = saved_source_level.unwrap_or(dzi_dimensions.max_level);
+    let mut source: Box<dyn RowSource + '_> = match saved_source_level {
+        Some(level) => {
+            println!("Reading layer {} back from disk...", get_level_name(&output.layout, &dzi_dimensions, level));
+            Box::new(SavedLevelRows::new(&output, &dzi_dimensions, level, tile_size, saved_tile_index))
+        }
+        None => source,
+    };
 
-    It would be good to figure out how many levels of zoom there are, because in DZI the smallest layer is 0 or 1, and the largest is the original image.
+    let archive = match cli.archive {
+        Some(format) => {
+            let archive_path = format!("{}/{}.{}", output.folder, output.stem, format.extension());
+            println!("Writing into {}...", archive_path);
+            Some(create_archive(format, &archive_path, &output, &dzi_dimensions))
+        }
+        None => {
+            write_descriptor(&output, &dzi_dimensions, tile_size, output.format.extension());
+            for level in min_level..=max_level {
+                let (cols, rows) = get_level_tile_count(&dzi_dimensions, level, tile_size);
+                for y in 0..rows {
+                    for x in 0..cols {
+                        if footprint.as_ref().is_some_and(|footprint| !footprint.contains(level, x, y)) {
+                            continue;
+                        }
+                        let tile_folder = get_tile_folder(&output, &dzi_dimensions, level, x, y);
+                        fs::create_dir_all(tile_folder).expect("Error creating level folder");
+                    }
+                }
+            }
+            None
+        }
+    };
 
-    */
+    /*
 
-    let mut layer_cols = dzi_dimensions.cols;
-    let mut layer_rows = dzi_dimensions.rows;
+    Every level is built in a single pass over the rows of the input image, which is read a band at a time.
 
+    Rows are handed to the top level a band at a time. As soon as a level has all the pixels of a
+    row of tiles, it cuts and saves them. As soon as it has a band of rows, plus a few rows below it
+    for the resize filter, it scales that band down by half and hands it to the level below, which
+    does the same thing.
 
-    // If 0, it's all layers. Else, do the target layer only
-    if layer_to_prepare == 0 {
-        for i in 0..dzi_dimensions.zoom_levels {
-            let next_layer = dzi_dimensions.zoom_levels - i - 1;
-
-            // just do one layer for testing
-            println!("Preparing scaled layer {}...", next_layer);
-            let start_time = Instant::now();
-            prepare_scaled_layer(next_layer, &output_file_stem, tile_size, &output_folder, layer_cols, layer_rows);
-            let end_time = Instant::now();
-            println!("Layer {} prepared in {:?}", next_layer, end_time.duration_since(start_time));
-
-            // Update the layer dimensions for the next layer
-            layer_cols = dzi_dimensions.cols.div_ceil(2_u32.pow(i+1));
-            layer_rows = dzi_dimensions.rows.div_ceil(2_u32.pow(i+1));
-        }
-    }
-}
+    Each level only holds on to the rows it still needs, and tiles are never read back from disk.
 
-fn calculate_zoom_levels(height: u32, width: u32, tile_size: u32) -> u32 {
+    */
 
-    let mut h = height;
-    let mut w = width;
+    println!(
+        "Preparing layers {} to {}...",
+        get_level_name(&output.layout, &dzi_dimensions, max_level),
+        get_level_name(&output.layout, &dzi_dimensions, min_level)
+    );
+    let start_time = Instant::now();
+    let mut tile_writer = TileWriter {
+        output: &output,
+        dzi_dimensions: &dzi_dimensions,
+        tile_size,
+        jobs: cli.jobs.max(1),
+        start_time,
+        progress,
+        archive,
+        is_tiff,
+        skip_empty: cli.skip_empty,
+        dedup: cli.dedup,
+        tile_index,
+        footprint: footprint.as_ref(),
+    };
+    // Where the input already has a smaller copy of the image at the size of a level, as in a pyramidal TIFF, the
+    // levels from there down are read from it rather than scaled down again
+    let rows_per_push = cli.buffer_rows.max(1) * tile_size;
+    let mut stage_top_level = top_level;
+    loop {
+        let reduced_source = (min_level..stage_top_level).rev().find_map(|level| {
+            let (level_width, level_height) = get_level_dimensions(&dzi_dimensions, level);
+            source.open_reduced_level(level_width, level_height).map(|reduced_source| (level, reduced_source))
+        });
+        let stage_min_level = match &reduced_source {
+            Some((level, _)) => level + 1,
+            None => min_level,
+        };
+
+        // Levels above the ones asked for are only built when something below them needs them
+        if stage_min_level <= max_level {
+            let mut builder = PyramidBuilder::new(
+                &dzi_dimensions,
+                tile_size,
+                cli.buffer_rows,
+                stage_top_level,
+                stage_min_level..=max_level,
+                filters,
+                &mut tile_writer,
+            )
+            .with_footprint(footprint.as_ref());
+            let mut rows = Vec::new();
+            while source.read_rows(rows_per_push, &mut rows) > 0 {
+                builder.push_rows(&rows);
+                rows.clear();
+            }
+            builder.finish();
+        }
 
-    let mut zoom_levels = 0;
-    while h > tile_size || w > tile_size {
-        h /= 2;
-        w /= 2;
-        zoom_levels += 1;
+        let Some((level, reduced_source)) = reduced_source else {
+            break;
+        };
+        println!("Reading layer {} from the input's own pyramid...", get_level_name(&output.layout, &dzi_dimensions, level));
+        source = reduced_source;
+        stage_top_level = level;
     }
-    return zoom_levels;
-}
-
-fn copy_pixels_to_tile(image: &Image<Rgba>, tile_size: u32, x: u32, y: u32) -> Image<Rgba> {
-    let mut tile = Image::new(tile_size, tile_size, Rgba { r: 0, g: 0, b: 0, a: 0 });
-    for i in 0..tile_size {
-        for j in 0..tile_size {
-            let mut new_pixel = Rgba { r: 0, g: 0, b: 0, a: 0 };
-            let source_pixel = image.get_pixel(x + i, y + j).unwrap_or(&Rgba { r: 0, g: 0, b: 0, a: 0 });
 
-            new_pixel.r = source_pixel.r;
-            new_pixel.g = source_pixel.g;
-            new_pixel.b = source_pixel.b;
-            new_pixel.a = source_pixel.a;
-
-            tile.set_pixel(i, j, new_pixel);
+    let extension = output.format.extension();
+
+    let needs_index = cli.skip_empty || cli.dedup == Some(DedupMode::Index) || footprint.is_some();
+    let tile_index = tile_writer.tile_index.to_json();
+    match (needs_index || !tile_writer.tile_index.is_empty(), &mut tile_writer.archive) {
+        (true, Some(archive)) => archive.add_file(&get_archive_entry_name(&output, &index_path), tile_index.as_bytes()),
+        (true, None) => fs::write(&index_path, &tile_index).expect("Error writing tile index"),
+        (false, _) => {
+            if Path::new(&index_path).exists() {
+                fs::remove_file(&index_path).expect("Error removing old tile index");
+            }
         }
     }
-    return tile;
-}
 
-fn get_file_name(x: u32, y: u32, z: u32, prefix: &str) -> String {
-    if prefix.is_empty() {
-        return format!("{}_{}_{}", z, y, x);
+    // Archives only take each file once, so their descriptor goes in last
+    if let Some(mut archive) = tile_writer.archive {
+        for (descriptor_path, descriptor) in get_descriptor_files(&output, &dzi_dimensions, tile_size, extension) {
+            archive.add_file(&get_archive_entry_name(&output, &descriptor_path), descriptor.as_bytes());
+        }
+        archive.finish();
+    } else if cli.emit_viewer {
+        write_viewer(&output, &dzi_dimensions, tile_size, extension, &viewer_channels, &tile_index);
+        println!("Viewer written to {}/index.html", output.folder);
     }
-    return format!("{}_{}_{}_{}", prefix, z, y, x);
+
+    let end_time = Instant::now();
+    println!("All layers prepared in {:?}", end_time.duration_since(start_time));
 }
 
-fn prepare_first_layer(image: &Image<Rgba>, tile_size: u32, dzi_dimensions: &DZIDimensions, output_folder: &str, prefix: &str) -> (){
-    for y in 0..dzi_dimensions.rows{
-        for x in 0..dzi_dimensions.cols {
-            let tile = copy_pixels_to_tile(image, tile_size, x * tile_size, y * tile_size);
-            let file_name = get_file_name(x, y, dzi_dimensions.zoom_levels, prefix);
-            let output_file = format!("{}/{}.png", output_folder, file_name);
-            tile.save(ril::ImageFormat::Png, output_file).expect("Error saving image");
-        }
+// Whether every tile of a level is on disk or in the tile index. Zarr chunks aren't images, so they can't be read back.
+fn is_level_complete(
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+    level: u32,
+    tile_size: u32,
+    left_out_tiles: &HashMap<String, IndexEntry>,
+) -> bool {
+    if matches!(output.layout, DZILayout::Zarr) {
+        return false;
     }
+    let (cols, rows) = get_level_tile_count(dzi_dimensions, level, tile_size);
+    return (0..rows).all(|y| {
+        (0..cols).all(|x| {
+            find_tile_path(output, dzi_dimensions, level, x, y).is_some()
+                || find_index_entry(left_out_tiles, output, dzi_dimensions, level, x, y).is_some()
+        })
+    });
 }
 
-// Loads an image, or creates a blank, black image
-fn get_image_or_blank(level: u32, x:u32, y:u32, prefix: &str, tile_size: u32, output_folder:&str)-> Image<Rgba>{
-    let image_name = get_file_name(x, y, level, prefix);
-    let image_path = format!("{}/{}.png", output_folder, image_name);
-    let result:Image<Rgba> = Image::open(image_path).unwrap_or(Image::new(tile_size, tile_size, Rgba::black())); 
-    return result
+// Everything that decides what the tiles look like, so a run is only resumed with the settings it was started with
+fn get_progress_settings(
+    input_image: &str,
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+    tile_size: u32,
+    filter: ReductionFilter,
+) -> String {
+    let input_size = fs::metadata(input_image).map(|metadata| metadata.len()).unwrap_or(0);
+    let name = |value: Option<PossibleValue>| value.expect("Every option has a name").get_name().to_string();
+    return format!(
+        "input={} input_size={} width={} height={} tile_size={} overlap={} layout={} format={} quality={} filter={}",
+        input_image.replace(' ', "%20"),
+        input_size,
+        dzi_dimensions.width,
+        dzi_dimensions.height,
+        tile_size,
+        dzi_dimensions.overlap,
+        name(output.layout.to_possible_value()),
+        name(output.format.to_possible_value()),
+        output.quality,
+        name(filter.to_possible_value())
+    );
 }
 
-// Loads four tiles, pastes them onto a new image, and returns it
-fn prepare_scaled_tile(level: u32, x:u32, y:u32, prefix: &str, tile_size:u32, output_folder:&str)-> Image<Rgba>{
-    let top_left = get_image_or_blank(level, x, y, prefix, tile_size, output_folder);
-    let top_right = get_image_or_blank(level, x+1, y, prefix, tile_size, output_folder);
-    let bottom_left = get_image_or_blank(level, x, y+1, prefix, tile_size, output_folder);
-    let bottom_right = get_image_or_blank(level, x+1, y+1, prefix, tile_size, output_folder);
-
-    let mut new_image = Image::new(tile_size*2, tile_size*2, Rgba::black());
-    new_image.paste(0, 0, &top_left);
-    new_image.paste(tile_size, 0, &top_right);
-    new_image.paste(0, tile_size, &bottom_left);
-    new_image.paste(tile_size, tile_size, &bottom_right);
-
-    new_image.resize(tile_size, tile_size, ril::ResizeAlgorithm::Bilinear);
-    return new_image;
+// Saves tiles under the output layout's file names as the pyramid builder cuts them
+struct TileWriter<'a> {
+    output: &'a DZIOutput,
+    dzi_dimensions: &'a DZIDimensions,
+    tile_size: u32,
+    // Threads used to encode and save the tiles of each row
+    jobs: u32,
+    start_time: Instant,
+    // Only kept with --resume
+    progress: Option<ProgressManifest>,
+    // Only kept with --archive
+    archive: Option<Box<dyn TileArchive>>,
+    // TIFF archives take raw pixels rather than image files
+    is_tiff: bool,
+    skip_empty: bool,
+    dedup: Option<DedupMode>,
+    // Tiles left out by skip_empty, dedup or the footprint
+    tile_index: TileIndex,
+    // Only kept with --roi or --mask
+    footprint: Option<&'a TileFootprint>,
 }
 
+impl TileSink for TileWriter<'_> {
+    fn write_tile_row(&mut self, level: u32, y: u32, tiles: Vec<(u32, Image<Rgba>)>) {
+        let output = self.output;
+        let dzi_dimensions = self.dzi_dimensions;
+
+        // The pyramid builder doesn't cut tiles outside the footprint, but the index still lists them
+        if let Some(footprint) = self.footprint {
+            let (cols, _) = get_level_tile_count(dzi_dimensions, level, self.tile_size);
+            for x in (0..cols).filter(|&x| !footprint.contains(level, x, y)) {
+                let tile_path = get_tile_path(output, dzi_dimensions, level, x, y, output.format.extension());
+                self.tile_index.add_outside_footprint(get_relative_path(output, &tile_path).to_string());
+            }
+        }
+
+        // Tiles a resumed run already finished keep their files
+        let mut tiles_to_write = Vec::new();
+        for (x, tile) in tiles {
+            if self.progress.as_ref().and_then(|progress| progress.finished_tile(level, x, y)).is_none() {
+                tiles_to_write.push((x, tile));
+            }
+        }
 
-fn prepare_scaled_layer(level: u32, prefix: &str, tile_size: u32, output_folder: &str, layer_cols: u32, layer_rows: u32){
-    for y in (0..layer_rows).step_by(2) {
-        for x in (0..layer_cols).step_by(2) {
-            let tile = prepare_scaled_tile(level+1, x, y, prefix, tile_size, output_folder);
-            let file_name = get_file_name(x/2, y/2, level, prefix);
-            let output_file = format!("{}/{}.png", output_folder, file_name);
-            tile.save(ril::ImageFormat::Png, output_file).expect("Error saving image");
+        // Tiles are saved on the worker threads, unless they have to go through the archive or the dedup check,
+        // which take one tile at a time once the row has been encoded
+        let tile_size = self.tile_size;
+        let skip_empty = self.skip_empty;
+        let is_tiff = self.is_tiff;
+        let save_in_order = output.is_archived || self.dedup.is_some();
+        let tiles_encoded = map_in_parallel(tiles_to_write, self.jobs, |(x, tile)| {
+            if let (true, Some(color)) = (skip_empty, get_blank_color(&tile)) {
+                return (x, Some(color), Vec::new());
+            }
+            if is_tiff {
+                return (x, None, encode_tiff_tile(&tile, tile_size));
+            }
+            if let DZILayout::Zarr = output.layout {
+                let bytes = encode_zarr_chunk(&tile, tile_size, output.channel_labels.len());
+                save_tile_file(&get_tile_path(output, dzi_dimensions, level, x, y, ""), &bytes);
+                return (x, None, bytes);
+            }
+            // Map viewers stretch every tile to the full tile size, so edge tiles are padded out with transparency
+            let tile = match output.layout {
+                DZILayout::Xyz | DZILayout::Tms => pad_tile(tile, tile_size),
+                _ => tile,
+            };
+            let bytes = encode_tile(&tile, output.format, output.quality);
+            if !save_in_order {
+                let output_file = get_tile_path(output, dzi_dimensions, level, x, y, output.format.extension());
+                save_tile_file(&output_file, &bytes);
+            }
+            (x, None, bytes)
+        });
+
+        let extension = output.format.extension();
+        for (x, blank_color, bytes) in tiles_encoded {
+            let tile_path = get_tile_path(output, dzi_dimensions, level, x, y, extension);
+            let tile_name = get_relative_path(output, &tile_path).to_string();
+            if let Some(color) = blank_color {
+                self.tile_index.add_blank(tile_name, color);
+                continue;
+            }
+
+            let duplicate_of = match self.dedup {
+                Some(_) => self.tile_index.find_duplicate(&tile_name, &bytes),
+                None => None,
+            };
+            match (duplicate_of, self.dedup) {
+                (Some(original), Some(DedupMode::Index)) => {
+                    self.tile_index.add_duplicate(tile_name, original);
+                    continue;
+                }
+                (Some(original), Some(DedupMode::Hardlink)) => {
+                    if Path::new(&tile_path).exists() {
+                        fs::remove_file(&tile_path).expect("Error replacing tile");
+                    }
+                    fs::hard_link(format!("{}/{}", output.folder, original), &tile_path).expect("Error linking tile");
+                }
+                _ => match &mut self.archive {
+                    Some(archive) => {
+                        let level_name = get_level_name(&output.layout, dzi_dimensions, level);
+                        archive.add_tile(level_name, x, y, &get_archive_entry_name(output, &tile_path), &bytes);
+                    }
+                    None if save_in_order => save_tile_file(&tile_path, &bytes),
+                    None => {}
+                },
+            }
+            if let Some(progress) = &mut self.progress {
+                progress.record_tile(level, x, y, extension, &bytes);
+            }
         }
+
+        let (_, layer_rows) = get_level_tile_count(self.dzi_dimensions, level, self.tile_size);
+        if y + 1 == layer_rows {
+            let level_name = get_level_name(&self.output.layout, self.dzi_dimensions, level);
+            println!("Layer {} prepared after {:?}", level_name, self.start_time.elapsed());
+            if let Some(progress) = &mut self.progress {
+                progress.record_level(level);
+            }
+        }
+        if let Some(progress) = &mut self.progress {
+            progress.flush();
+        }
+    }
+}
+
+// Writes a tile's file. Earlier runs with --dedup hardlink can leave tiles sharing one file, so the old file is
+// unlinked rather than written over.
+fn save_tile_file(path: &str, bytes: &[u8]) {
+    if Path::new(path).exists() {
+        fs::remove_file(path).expect("Error replacing tile");
     }
+    fs::write(path, bytes).expect("Error saving image");
 }
 
+// Extends an edge tile to a full tile_size square, keeping it in the top left corner
+pub fn pad_tile(tile: Image<Rgba>, tile_size: u32) -> Image<Rgba> {
+    if tile.width() == tile_size && tile.height() == tile_size {
+        return tile;
+    }
+    return Image::from_fn(tile_size, tile_size, |x, y| {
+        if x < tile.width() && y < tile.height() {
+            *tile.pixel(x, y)
+        } else {
+            Rgba { r: 0, g: 0, b: 0, a: 0 }
+        }
+    });
+}
 
-/* 
-To prepare the next layer, I need to:
-take the width and height of the previous layer
-divide the width and half in two, and use this as the loop values
-attempt to load x1, x2, y1, and y2 tiles - if a tile doesn't load, a blank tile should be used
-create a new image that is the w2 and h2,
-paste the x1, x2, y1, and y2 tiles onto the new image
-scale the new image down by half
-save it using the next layer's number
+/// # Map in parallel
+/// Given a list of items, run a task over each of them on up to `jobs` threads, and return the results in the original order.
+///
+/// Each thread takes one contiguous run of the items, so the work done for an item never depends on how many threads there are.
+pub fn map_in_parallel<T: Send, R: Send>(items: Vec<T>, jobs: u32, task: impl Fn(T) -> R + Sync) -> Vec<R> {
+    if jobs <= 1 || items.len() <= 1 {
+        return items.into_iter().map(task).collect();
+    }
 
-this is then repeated until the layer is 1x1
+    let chunk_size = items.len().div_ceil(jobs as usize);
+    let mut chunks = Vec::new();
+    let mut remaining = items;
+    while !remaining.is_empty() {
+        let rest = remaining.split_off(chunk_size.min(remaining.len()));
+        chunks.push(remaining);
+        remaining = rest;
+    }
 
-*/
\ No newline at end of file
+    let task = &task;
+    return std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(task).collect::<Vec<R>>()))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Tile worker panicked"))
+            .collect()
+    });
+}
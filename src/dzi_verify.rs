@@ -0,0 +1,197 @@
+use ril::{Image, Rgba};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::app;
+use crate::dzi_layout::{
+    get_descriptor_path, get_lowest_level, get_relative_path, get_tile_path, read_descriptor, DZILayout, DZIOutput,
+};
+use crate::dzi_pyramid::{
+    calculate_max_level, calculate_single_tile_levels, calculate_zoom_levels, get_level_tile_count, get_tile_bounds,
+    DZIDimensions,
+};
+use crate::dzi_sparse::read_tile_index;
+use crate::dzi_split_mode::map_in_parallel;
+use crate::tile_format::TileFormat;
+
+const TILE_EXTENSIONS: [&str; 3] = ["png", "jpg", "webp"];
+
+// A tile that is on disk, with the size it should be
+struct FoundTile {
+    path: String,
+    width: u32,
+    height: u32,
+}
+
+pub fn do_dzi_verify(cli: app::DZIVerifyArgs) {
+    println!("Verifying {}...", cli.output_folder);
+    if let DZILayout::Zarr = cli.layout {
+        panic!("Zarr stores hold raw chunks rather than tile images. Open them with a Zarr reader instead.");
+    }
+
+    let mut output = DZIOutput {
+        layout: cli.layout,
+        folder: cli.output_folder.trim_end_matches('/').to_string(),
+        stem: cli.output_file_stem,
+        format: TileFormat::Png,
+        quality: 0,
+        tile_size: 0,
+        base_url: String::new(),
+        is_archived: false,
+        channel_labels: Vec::new(),
+    };
+    let mut problems = Vec::new();
+
+    // The descriptor has the image size, but options given on the command line win
+    let descriptor = match get_descriptor_path(&output) {
+        Some(descriptor_path) => match fs::read_to_string(&descriptor_path) {
+            Ok(text) => read_descriptor(&output.layout, &text),
+            Err(_) => {
+                problems.push(format!("Missing descriptor: {}", descriptor_path));
+                Default::default()
+            }
+        },
+        None => Default::default(),
+    };
+    let (Some(width), Some(height)) = (cli.width.or(descriptor.width), cli.height.or(descriptor.height)) else {
+        panic!("The image size is unknown. Without a descriptor, pass --width and --height.");
+    };
+    let tile_size = cli.tile_size.or(descriptor.tile_size).unwrap_or(256);
+    let overlap = cli.overlap.or(descriptor.overlap).unwrap_or(0);
+    output.tile_size = tile_size;
+
+    let dzi_dimensions = DZIDimensions {
+        width,
+        height,
+        zoom_levels: calculate_zoom_levels(height, width, tile_size),
+        single_tile_levels: calculate_single_tile_levels(height, width, tile_size),
+        max_level: calculate_max_level(height, width),
+        overlap,
+    };
+
+    // The descriptor's format comes first, and tiles in any other format are reported as being in the wrong one
+    let described_extension = descriptor.extension.as_deref();
+    let mut extensions: Vec<&str> = described_extension.into_iter().collect();
+    extensions.extend(TILE_EXTENSIONS.iter().filter(|&&extension| Some(extension) != described_extension));
+
+    let left_out_tiles = read_tile_index(&format!("{}/{}_index.json", output.folder, output.stem));
+
+    let mut found_tiles = Vec::new();
+    let lowest_level = get_lowest_level(&output.layout, &dzi_dimensions);
+    for level in lowest_level..=dzi_dimensions.max_level {
+        let (cols, rows) = get_level_tile_count(&dzi_dimensions, level, tile_size);
+        for y in 0..rows {
+            for x in 0..cols {
+                let paths: Vec<String> = extensions
+                    .iter()
+                    .map(|extension| get_tile_path(&output, &dzi_dimensions, level, x, y, extension))
+                    .collect();
+                match paths.iter().find(|path| Path::new(path).exists()) {
+                    Some(path) => {
+                        if path != &paths[0] && described_extension.is_some() {
+                            problems.push(format!("Wrong format: {}, the descriptor names {}", path, extensions[0]));
+                        }
+                        // Map tiles are padded out to full squares, the rest are cropped to the image
+                        let (width, height) = match output.layout {
+                            DZILayout::Xyz | DZILayout::Tms => (tile_size, tile_size),
+                            _ => {
+                                let bounds = get_tile_bounds(&dzi_dimensions, level, x, y, tile_size);
+                                (bounds.width, bounds.height)
+                            }
+                        };
+                        found_tiles.push(FoundTile {
+                            path: path.clone(),
+                            width,
+                            height,
+                        });
+                    }
+                    None => {
+                        let is_left_out = paths
+                            .iter()
+                            .any(|path| left_out_tiles.contains_key(get_relative_path(&output, path)));
+                        if !is_left_out {
+                            problems.push(format!("Missing tile: {}", paths[0]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let expected_paths: HashSet<String> = found_tiles.iter().map(|tile| tile.path.clone()).collect();
+    let tile_count = found_tiles.len();
+    let tile_problems = map_in_parallel(found_tiles, cli.jobs.max(1), |tile| {
+        return match Image::<Rgba>::open(&tile.path) {
+            Err(error) => Some(format!("Corrupt tile: {} ({})", tile.path, error)),
+            Ok(image) if image.width() != tile.width || image.height() != tile.height => Some(format!(
+                "Wrong size: {} is {}x{}, expected {}x{}",
+                tile.path,
+                image.width(),
+                image.height(),
+                tile.width,
+                tile.height
+            )),
+            Ok(_) => None,
+        };
+    });
+    problems.extend(tile_problems.into_iter().flatten());
+
+    let mut extra_tiles = Vec::new();
+    find_tile_files(&output, &get_tile_root(&output), &mut extra_tiles);
+    extra_tiles.retain(|path| !expected_paths.contains(path));
+    extra_tiles.sort();
+    problems.extend(extra_tiles.into_iter().map(|path| format!("Extra tile: {}", path)));
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} tiles in {} levels, {} left out on purpose.",
+        tile_count,
+        dzi_dimensions.max_level - lowest_level + 1,
+        left_out_tiles.len()
+    );
+    if !problems.is_empty() {
+        println!("Found {} problems.", problems.len());
+        std::process::exit(1);
+    }
+    println!("The pyramid is complete.");
+}
+
+// The folder holding every tile of the layout, and nothing but tiles and the descriptor
+fn get_tile_root(output: &DZIOutput) -> String {
+    match output.layout {
+        DZILayout::Dzi => format!("{}/{}_files", output.folder, output.stem),
+        DZILayout::Legacy => output.folder.clone(),
+        _ => format!("{}/{}", output.folder, output.stem),
+    }
+}
+
+// Every image file under a folder. Legacy tiles share the output folder, so only their own file names count.
+fn find_tile_files(output: &DZIOutput, folder: &str, tile_files: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = format!("{}/{}", folder, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            if let DZILayout::Legacy = output.layout {
+                continue;
+            }
+            find_tile_files(output, &path, tile_files);
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let is_image = file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| TILE_EXTENSIONS.contains(&extension));
+        let is_own_file = match output.layout {
+            DZILayout::Legacy => file_name.starts_with(&format!("{}_", output.stem)),
+            _ => true,
+        };
+        if is_image && is_own_file {
+            tile_files.push(path);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use ril::{Image, Rgba};
+use std::fs;
+
+use crate::bitmask_mode::CollapseMode;
+use crate::dzi_layout::{get_lowest_level, DZILayout, DZIOutput};
+use crate::dzi_pyramid::DZIDimensions;
+
+const VIEWER_TEMPLATE: &str = include_str!("dzi_viewer.html");
+
+// What the viewer shows of one channel of a bitmask-mode image
+pub struct ViewerChannel {
+    // 0 for red, 1 for green and 2 for blue
+    pub index: usize,
+    pub label: String,
+    pub mode: CollapseMode,
+    // The bits that are set anywhere in a bitmask channel, one per class
+    pub classes: Vec<u32>,
+    // The highest value in the channel, which heatmaps are scaled to
+    pub max: u8,
+}
+
+/// # Find viewer channels
+/// Given a merged bitmask-mode image and the label and mode of its red, green and blue channels in that order, return
+/// the class metadata the viewer builds its toggles from. Skipped channels are left out.
+pub fn find_viewer_channels(image: &Image<Rgba>, channels: Vec<(String, CollapseMode)>) -> Vec<ViewerChannel> {
+    let (mut bits, mut max) = ([0_u8; 3], [0_u8; 3]);
+    for pixel in &image.data {
+        for (index, value) in [pixel.r, pixel.g, pixel.b].into_iter().enumerate() {
+            bits[index] |= value;
+            max[index] = max[index].max(value);
+        }
+    }
+
+    return channels
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (_, mode))| !matches!(mode, CollapseMode::Skip))
+        .map(|(index, (label, mode))| ViewerChannel {
+            index,
+            label,
+            classes: match mode {
+                CollapseMode::Bitmask => (0..8).filter(|bit| bits[index] & (1 << bit) != 0).collect(),
+                _ => Vec::new(),
+            },
+            mode,
+            max: max[index],
+        })
+        .collect();
+}
+
+/// # Write viewer
+/// Writes an `index.html` into the output folder that shows the pyramid with nothing but the files next to it, so it
+/// works from `file://` without a network. Since such a page can't fetch the descriptor or the tile index, what
+/// they say is written into the page. Given the channels of a bitmask-mode image, it also gets a toggle per class.
+pub fn write_viewer(
+    output: &DZIOutput,
+    dzi_dimensions: &DZIDimensions,
+    tile_size: u32,
+    extension: &str,
+    channels: &[ViewerChannel],
+    tile_index: &str,
+) {
+    let layout = match output.layout {
+        DZILayout::Dzi => "dzi",
+        DZILayout::Legacy => "legacy",
+        DZILayout::Xyz => "xyz",
+        DZILayout::Tms => "tms",
+        _ => panic!("The viewer can only show the dzi, legacy, xyz and tms layouts."),
+    };
+    let channels: Vec<String> = channels
+        .iter()
+        .map(|channel| {
+            let mode = match channel.mode {
+                CollapseMode::Bitmask => "bitmask",
+                CollapseMode::Heatmap => "heatmap",
+                CollapseMode::PassThrough => "pass-through",
+                CollapseMode::Skip => "skip",
+            };
+            let classes: Vec<String> = channel.classes.iter().map(|bit| bit.to_string()).collect();
+            format!(
+                "    {{ \"index\": {}, \"label\": {}, \"mode\": \"{}\", \"classes\": [{}], \"max\": {} }}",
+                channel.index,
+                get_json_string(&channel.label),
+                mode,
+                classes.join(", "),
+                channel.max
+            )
+        })
+        .collect();
+    let config = format!(
+        concat!(
+            "{{\n",
+            "  \"stem\": {},\n",
+            "  \"layout\": \"{}\",\n",
+            "  \"width\": {},\n",
+            "  \"height\": {},\n",
+            "  \"tileSize\": {},\n",
+            "  \"overlap\": {},\n",
+            "  \"extension\": \"{}\",\n",
+            "  \"lowestLevel\": {},\n",
+            "  \"maxLevel\": {},\n",
+            "  \"channels\": [\n{}\n  ],\n",
+            "  \"tileIndex\": {}\n",
+            "}}"
+        ),
+        get_json_string(&output.stem),
+        layout,
+        dzi_dimensions.width,
+        dzi_dimensions.height,
+        tile_size,
+        dzi_dimensions.overlap,
+        extension,
+        get_lowest_level(&output.layout, dzi_dimensions),
+        dzi_dimensions.max_level,
+        channels.join(",\n"),
+        tile_index.trim().replace("</", "<\\/")
+    );
+
+    let page = VIEWER_TEMPLATE
+        .replace("__TITLE__", &output.stem.replace('&', "&amp;").replace('<', "&lt;"))
+        .replace("__CONFIG__", &config);
+    fs::write(format!("{}/index.html", output.folder), page).expect("Error writing viewer");
+}
+
+// A JSON string that is also safe inside a script tag
+fn get_json_string(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"").replace('<', "\\u003c");
+    return format!("\"{}\"", escaped);
+}
@@ -0,0 +1,127 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use ril::{Image, Rgba};
+use std::io::Write;
+
+use crate::dzi_layout::DZIOutput;
+use crate::dzi_pyramid::{get_level_dimensions, DZIDimensions};
+
+// Channels are stored in RGBA order, as many of them as there are labels
+const CHANNEL_COLORS: [&str; 4] = ["FF0000", "00FF00", "0000FF", "FFFFFF"];
+
+// The store's folder in the output folder
+pub fn get_zarr_root(output: &DZIOutput) -> String {
+    return format!("{}/{}.zarr", output.folder, output.stem);
+}
+
+// The array a level is kept in, counting from 0 at full resolution as NGFF does
+pub fn get_zarr_array_name(dzi_dimensions: &DZIDimensions, level: u32) -> u32 {
+    return dzi_dimensions.max_level - level;
+}
+
+/// # Zarr metadata
+/// The files that describe an OME-NGFF 0.4 multiscale image as Zarr v2, as paths and contents: the group, its
+/// `multiscales` and `omero` channel attributes, and one array per level from full resolution down to the single-tile
+/// level. Each array is shaped (c, y, x) and chunked into tile_size squares holding every channel.
+pub fn get_zarr_metadata(output: &DZIOutput, dzi_dimensions: &DZIDimensions, tile_size: u32) -> Vec<(String, String)> {
+    let root = get_zarr_root(output);
+    let channel_count = output.channel_labels.len();
+    let levels = (dzi_dimensions.max_level - dzi_dimensions.single_tile_levels)..=dzi_dimensions.max_level;
+
+    let datasets: Vec<String> = levels
+        .clone()
+        .rev()
+        .map(|level| {
+            let array_name = get_zarr_array_name(dzi_dimensions, level);
+            let scale = 1_u32 << array_name;
+            format!(
+                "        {{ \"path\": \"{}\", \"coordinateTransformations\": [{{ \"type\": \"scale\", \"scale\": [1, {}, {}] }}] }}",
+                array_name, scale, scale
+            )
+        })
+        .collect();
+    let channels: Vec<String> = output
+        .channel_labels
+        .iter()
+        .zip(CHANNEL_COLORS)
+        .map(|(label, color)| {
+            format!(
+                "      {{ \"label\": \"{}\", \"color\": \"{}\", \"active\": true, \"window\": {{ \"start\": 0, \"end\": 255, \"min\": 0, \"max\": 255 }} }}",
+                label.replace(['"', '\\'], "'"),
+                color
+            )
+        })
+        .collect();
+    let attributes = format!(
+        concat!(
+            "{{\n",
+            "  \"multiscales\": [\n",
+            "    {{\n",
+            "      \"version\": \"0.4\",\n",
+            "      \"name\": \"{}\",\n",
+            "      \"axes\": [\n",
+            "        {{ \"name\": \"c\", \"type\": \"channel\" }},\n",
+            "        {{ \"name\": \"y\", \"type\": \"space\" }},\n",
+            "        {{ \"name\": \"x\", \"type\": \"space\" }}\n",
+            "      ],\n",
+            "      \"datasets\": [\n",
+            "{}\n",
+            "      ]\n",
+            "    }}\n",
+            "  ],\n",
+            "  \"omero\": {{\n",
+            "    \"channels\": [\n",
+            "{}\n",
+            "    ],\n",
+            "    \"rdefs\": {{ \"model\": \"color\" }}\n",
+            "  }}\n",
+            "}}\n"
+        ),
+        output.stem,
+        datasets.join(",\n"),
+        channels.join(",\n")
+    );
+
+    let mut files = vec![
+        (format!("{}/.zgroup", root), "{\n  \"zarr_format\": 2\n}\n".to_string()),
+        (format!("{}/.zattrs", root), attributes),
+    ];
+    for level in levels {
+        let (width, height) = get_level_dimensions(dzi_dimensions, level);
+        let array = format!(
+            concat!(
+                "{{\n",
+                "  \"zarr_format\": 2,\n",
+                "  \"shape\": [{}, {}, {}],\n",
+                "  \"chunks\": [{}, {}, {}],\n",
+                "  \"dtype\": \"|u1\",\n",
+                "  \"compressor\": {{ \"id\": \"zlib\", \"level\": 6 }},\n",
+                "  \"fill_value\": 0,\n",
+                "  \"order\": \"C\",\n",
+                "  \"filters\": null,\n",
+                "  \"dimension_separator\": \"/\"\n",
+                "}}\n"
+            ),
+            channel_count, height, width, channel_count, tile_size, tile_size
+        );
+        files.push((format!("{}/{}/.zarray", root, get_zarr_array_name(dzi_dimensions, level)), array));
+    }
+    return files;
+}
+
+/// # Encode Zarr chunk
+/// Given a tile, return it as a chunk of its level's array: each channel in turn as a full tile_size square of bytes,
+/// padded with the fill value past the edge of the image, and zlib compressed.
+pub fn encode_zarr_chunk(tile: &Image<Rgba>, tile_size: u32, channel_count: usize) -> Vec<u8> {
+    let channels: [fn(&Rgba) -> u8; 4] = [|pixel| pixel.r, |pixel| pixel.g, |pixel| pixel.b, |pixel| pixel.a];
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut row = Vec::with_capacity(tile_size as usize);
+    for channel in &channels[..channel_count] {
+        for y in 0..tile_size {
+            row.clear();
+            row.extend((0..tile_size).map(|x| tile.get_pixel(x, y).map(channel).unwrap_or(0)));
+            encoder.write_all(&row).expect("Error compressing chunk");
+        }
+    }
+    return encoder.finish().expect("Error compressing chunk");
+}
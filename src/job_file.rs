@@ -0,0 +1,123 @@
+use clap::CommandFactory;
+use std::fs;
+use toml::Value;
+
+use crate::app;
+
+// Subcommands that take --config
+const JOB_FILE_COMMANDS: [&str; 1] = ["bitmask-mode"];
+
+/// # Expand job file
+/// Given the command line, return it with the options from any `--config` job file added, except for the ones that
+/// are also given on the command line, which win.
+///
+/// A job file is TOML, or JSON if its name ends in `.json`, and its keys are the long option names, such as
+/// `red-path = "cutoff.png"` or `red-bbox = [0, 0, 100, 100]`. Named profiles go in a `profiles` table, and the one
+/// `--profile` picks overrides the values at the top of the file.
+pub fn expand_job_file(args: Vec<String>) -> Vec<String> {
+    if args.len() < 2 || !JOB_FILE_COMMANDS.contains(&args[1].as_str()) {
+        return args;
+    }
+    let Some(config_path) = find_option(&args, "--config") else {
+        return args;
+    };
+    let profile = find_option(&args, "--profile");
+
+    let text = fs::read_to_string(&config_path).expect("Error reading job file");
+    let job: Value = if config_path.to_lowercase().ends_with(".json") {
+        serde_json::from_str(&text).expect("Error parsing JSON job file")
+    } else {
+        toml::from_str(&text).expect("Error parsing TOML job file")
+    };
+    let Value::Table(mut job) = job else {
+        panic!("A job file has to hold a table of options.");
+    };
+
+    let profiles = job.remove("profiles");
+    if let Some(profile) = &profile {
+        let Some(Value::Table(profile_options)) = profiles.as_ref().and_then(|profiles| profiles.get(profile)) else {
+            let names: Vec<&String> = match &profiles {
+                Some(Value::Table(profiles)) => profiles.keys().collect(),
+                _ => Vec::new(),
+            };
+            panic!("The job file has no profile named {}. It has: {:?}", profile, names);
+        };
+        job.extend(profile_options.clone());
+    }
+    for name in get_given_options(&args[1], &args[2..]) {
+        job.remove(&name);
+    }
+    match &profile {
+        Some(profile) => println!("Using job file {}, profile {}", config_path, profile),
+        None => println!("Using job file {}", config_path),
+    }
+
+    let mut expanded_args = args[..2].to_vec();
+    expanded_args.extend(get_option_args(job));
+    expanded_args.extend_from_slice(&args[2..]);
+    return expanded_args;
+}
+
+// The value of an option given as `--name value` or `--name=value`
+fn find_option(args: &[String], name: &str) -> Option<String> {
+    for (index, arg) in args.iter().enumerate() {
+        if arg == name {
+            return args.get(index + 1).cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&format!("{}=", name)) {
+            return Some(value.to_string());
+        }
+    }
+    return None;
+}
+
+// The long names of the options given on the command line, including those given by their short names
+fn get_given_options(command_name: &str, args: &[String]) -> Vec<String> {
+    let command = app::Cli::command();
+    let subcommand = command.find_subcommand(command_name).expect("Job files are only read for known subcommands");
+
+    let mut names = Vec::new();
+    for arg in args {
+        if let Some(long) = arg.strip_prefix("--") {
+            names.push(long.split('=').next().unwrap_or(long).to_string());
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // Short flags can be bunched together, as in -dr, and whatever follows one that takes a value is its value
+            for short in shorts.chars() {
+                let Some(option) = subcommand.get_arguments().find(|option| option.get_short() == Some(short)) else {
+                    break;
+                };
+                names.extend(option.get_long().map(|long| long.to_string()));
+                if option.get_action().takes_values() {
+                    break;
+                }
+            }
+        }
+    }
+    return names;
+}
+
+// Turns each option in the file into the arguments that would give it on the command line. A flag that is false is
+// left out.
+fn get_option_args(options: toml::Table) -> Vec<String> {
+    let mut args = Vec::new();
+    for (name, value) in options {
+        let values = match value {
+            Value::Boolean(false) => continue,
+            Value::Boolean(true) => Vec::new(),
+            Value::Array(items) => items.into_iter().map(|item| get_value_arg(&name, item)).collect(),
+            value => vec![get_value_arg(&name, value)],
+        };
+        args.push(format!("--{}", name));
+        args.extend(values);
+    }
+    return args;
+}
+
+fn get_value_arg(name: &str, value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        Value::Integer(number) => number.to_string(),
+        Value::Float(number) => number.to_string(),
+        _ => panic!("The job file option {} has to be a string, a number, a list of them, or true or false.", name),
+    }
+}
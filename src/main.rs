@@ -1,15 +1,36 @@
-use clap::{Parser};
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
 
 mod app;
+mod bitmask_dzi_mode;
 mod bitmask_mode;
+mod dzi_archive;
+mod dzi_footprint;
+mod dzi_layout;
+mod dzi_progress;
+mod dzi_pyramid;
+mod dzi_reassemble;
+mod dzi_serve;
+mod dzi_source;
+mod dzi_sparse;
 mod dzi_split_mode;
+mod dzi_verify;
+mod dzi_viewer;
+mod dzi_zarr;
+mod job_file;
+mod tile_format;
 
 
 fn main() {
-    let cli: app::Cli = app::Cli::parse();
+    let cli: app::Cli = app::Cli::parse_from(job_file::expand_job_file(std::env::args().collect()));
 
     match cli.command {
         app::Commands::BitmaskMode(args) => bitmask_mode::do_bitmask_mode(args),
+        app::Commands::BitmaskDZIMode(args) => bitmask_dzi_mode::do_bitmask_dzi_mode(args),
         app::Commands::DZISplitMode(args) => dzi_split_mode::do_dzi_split_mode(args),
+        app::Commands::DZIVerify(args) => dzi_verify::do_dzi_verify(args),
+        app::Commands::DZIReassemble(args) => dzi_reassemble::do_dzi_reassemble(args),
+        app::Commands::Serve(args) => dzi_serve::do_serve(args),
     }
 }
@@ -0,0 +1,90 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use ril::encodings::jpeg::{JpegEncoder, JpegEncoderOptions};
+use ril::encodings::webp::{WebPEncoderOptions, WebPStaticEncoder};
+use ril::{Encoder, EncoderMetadata, Image, Rgba};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileFormat {
+    Png,
+    Jpeg,
+    Webp,
+    /// PNG for images with any transparency, JPEG for fully opaque images
+    Auto,
+}
+
+impl ValueEnum for TileFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[TileFormat::Png, TileFormat::Jpeg, TileFormat::Webp, TileFormat::Auto]
+    }
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            TileFormat::Png => Some(PossibleValue::new("png")),
+            TileFormat::Jpeg => Some(PossibleValue::new("jpeg")),
+            TileFormat::Webp => Some(PossibleValue::new("webp")),
+            TileFormat::Auto => Some(PossibleValue::new("auto")),
+        }
+    }
+}
+
+impl TileFormat {
+    /// The file extension, which is also the name DZI descriptors use for the format.
+    /// Auto has no extension of its own, so it reports JPEG, the format of an opaque tile.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpeg | TileFormat::Auto => "jpg",
+            TileFormat::Webp => "webp",
+        }
+    }
+
+    /// The format that writes files with the given extension
+    pub fn from_extension(extension: &str) -> Option<TileFormat> {
+        match extension {
+            "png" => Some(TileFormat::Png),
+            "jpg" => Some(TileFormat::Jpeg),
+            "webp" => Some(TileFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// The format every tile of an image is written in, which only differs from self for Auto. A descriptor can only
+    /// name one format, so auto decides once for the whole image: PNG if it has any transparency, JPEG otherwise.
+    pub fn resolve(&self, is_opaque: bool) -> TileFormat {
+        match self {
+            TileFormat::Auto if is_opaque => TileFormat::Jpeg,
+            TileFormat::Auto => TileFormat::Png,
+            format => *format,
+        }
+    }
+}
+
+/// # Encode tile
+/// Given a tile, a format and a quality, return the encoded bytes.
+///
+/// Quality only applies to JPEG and WebP. JPEG has no alpha channel, so it is dropped. Auto has to be resolved for
+/// the whole image first.
+pub fn encode_tile(tile: &Image<Rgba>, format: TileFormat, quality: u8) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    match format {
+        TileFormat::Png => {
+            tile.encode(ril::ImageFormat::Png, &mut bytes).expect("Error encoding PNG tile");
+        }
+        TileFormat::Jpeg => {
+            let options = JpegEncoderOptions::new().with_quality(quality);
+            let metadata = EncoderMetadata::from(tile).with_config(options);
+            let mut encoder = JpegEncoder::new(&mut bytes, metadata).expect("Error encoding JPEG tile");
+            encoder.add_frame(tile).expect("Error encoding JPEG tile");
+            encoder.finish().expect("Error encoding JPEG tile");
+        }
+        TileFormat::Webp => {
+            let options = WebPEncoderOptions::new().with_quality(quality as f32);
+            let metadata = EncoderMetadata::from(tile).with_config(options);
+            let mut encoder = WebPStaticEncoder::new(&mut bytes, metadata).expect("Error encoding WebP tile");
+            encoder.add_frame(tile).expect("Error encoding WebP tile");
+            encoder.finish().expect("Error encoding WebP tile");
+        }
+        TileFormat::Auto => panic!("Auto picks a format for the whole image before any tile is encoded"),
+    }
+    return bytes;
+}